@@ -0,0 +1,71 @@
+//! Opens many registered but idle client connections to an in-process
+//! server and reports how much CPU the process burns while they sit there.
+//!
+//!     IDLE_CONNECTIONS=10000 IDLE_SECONDS=10 cargo bench --bench idle_connections
+//!
+//! Each connection needs two file descriptors here, one for each end, so
+//! raise `ulimit -n` to match.
+extern crate rustirc;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustirc::{IrcServer, Config, Listener};
+
+const PORT: u16 = 16667;
+/// USER_HZ, the unit of the times in /proc/self/stat, on every mainstream
+/// Linux configuration.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+fn env_or(name: &str, default: u64) -> u64 {
+	env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// User plus system CPU time used so far by this process, in clock ticks.
+fn cpu_ticks() -> u64 {
+	let stat = fs::read_to_string("/proc/self/stat").expect("this benchmark needs /proc");
+	// skip past the command name, which may itself contain spaces; utime
+	// and stime are then the 12th and 13th fields
+	let fields : Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split_whitespace().collect();
+	fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+fn connect() -> TcpStream {
+	for _ in 0..50 {
+		if let Ok(stream) = TcpStream::connect(("127.0.0.1", PORT)) {
+			return stream;
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+	panic!("couldn't connect to the server; is the file descriptor limit high enough?");
+}
+
+fn main() {
+	let num_connections = env_or("IDLE_CONNECTIONS", 1000);
+	let seconds = env_or("IDLE_SECONDS", 5);
+
+	let listener = Listener::new(SocketAddr::from(([127, 0, 0, 1], PORT)), false);
+	thread::spawn(|| IrcServer::new(Config {listeners: vec![listener], ..Config::default()}).unwrap().run());
+
+	let mut clients = vec![];
+	for i in 0..num_connections {
+		let mut stream = connect();
+		write!(stream, "NICK idle{}\r\nUSER idle 0 * :Idle client\r\n", i).unwrap();
+		clients.push(stream);
+	}
+	// let the registration bursts go out before measuring
+	thread::sleep(Duration::from_secs(1));
+
+	let start_ticks = cpu_ticks();
+	let start = Instant::now();
+	thread::sleep(Duration::from_secs(seconds));
+	let cpu_seconds = (cpu_ticks() - start_ticks) as f64 / CLOCK_TICKS_PER_SECOND;
+	let elapsed = start.elapsed().as_secs_f64();
+
+	println!("{} idle connections: {:.2}s of CPU in {:.2}s ({:.1}% of one core)",
+		clients.len(), cpu_seconds, elapsed, 100.0 * cpu_seconds / elapsed);
+}
@@ -0,0 +1,184 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use unicode_normalization::UnicodeNormalization;
+
+/// How nicknames and channel names are compared, as advertised in the
+/// CASEMAPPING ISUPPORT token.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CaseMapping {
+	/// Only A-Z and a-z are equivalent.
+	Ascii,
+	/// As ascii, with `[]\~` the upper case of `{}|^`.
+	#[default]
+	Rfc1459,
+	/// As rfc1459, without the `~` and `^` pair.
+	StrictRfc1459,
+	/// Unicode names, width-mapped, lowercased and NFC-normalized after the
+	/// PRECIS UsernameCaseMapped profile.
+	Rfc7613,
+}
+
+impl CaseMapping {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"ascii" => Some(CaseMapping::Ascii),
+			"rfc1459" => Some(CaseMapping::Rfc1459),
+			"strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+			"rfc7613" => Some(CaseMapping::Rfc7613),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			CaseMapping::Ascii => "ascii",
+			CaseMapping::Rfc1459 => "rfc1459",
+			CaseMapping::StrictRfc1459 => "strict-rfc1459",
+			CaseMapping::Rfc7613 => "rfc7613",
+		}
+	}
+
+	/// The form of a name that is compared and looked up.
+	pub fn fold(self, name: &str) -> String {
+		match self {
+			CaseMapping::Ascii => name.to_ascii_lowercase(),
+			CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459 => name.chars()
+				.map(|c| match c {
+					'[' => '{',
+					']' => '}',
+					'\\' => '|',
+					'~' if self == CaseMapping::Rfc1459 => '^',
+					other => other.to_ascii_lowercase(),
+				})
+				.collect(),
+			CaseMapping::Rfc7613 => name.chars()
+				.flat_map(|c| if is_width_variant(c) {
+					c.to_string().nfkc().collect::<Vec<char>>()
+				} else {
+					vec![c]
+				})
+				.collect::<String>()
+				.to_lowercase()
+				.nfc()
+				.collect(),
+		}
+	}
+
+	pub fn key(self, name: &str) -> CaseKey {
+		CaseKey {display: name.to_string(), folded: self.fold(name)}
+	}
+}
+
+/// Halfwidth and fullwidth forms, which PRECIS maps to their decompositions.
+fn is_width_variant(c: char) -> bool {
+	('\u{ff00}'..='\u{ffef}').contains(&c)
+}
+
+/// A nickname or channel name that keeps the casing it was given for
+/// display, but hashes and compares by its case-folded form. Maps keyed by
+/// it can be queried with an already folded `&str`.
+#[derive(Clone, Debug)]
+pub struct CaseKey {
+	display: String,
+	folded: String,
+}
+
+impl CaseKey {
+	pub fn as_str(&self) -> &str {
+		&self.display
+	}
+
+	pub fn folded(&self) -> &str {
+		&self.folded
+	}
+}
+
+impl fmt::Display for CaseKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.display)
+	}
+}
+
+impl PartialEq for CaseKey {
+	fn eq(&self, other: &CaseKey) -> bool {
+		self.folded == other.folded
+	}
+}
+
+impl Eq for CaseKey {}
+
+impl Hash for CaseKey {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.folded.hash(state);
+	}
+}
+
+impl PartialOrd for CaseKey {
+	fn partial_cmp(&self, other: &CaseKey) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for CaseKey {
+	fn cmp(&self, other: &CaseKey) -> Ordering {
+		self.folded.cmp(&other.folded)
+	}
+}
+
+impl Borrow<str> for CaseKey {
+	fn borrow(&self) -> &str {
+		&self.folded
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ascii_folds_letters_only() {
+		assert_eq!(CaseMapping::Ascii.fold("NiCk[]\\~"), "nick[]\\~");
+		assert_eq!(CaseMapping::Ascii.fold("ÉLISE"), "Élise");
+	}
+
+	#[test]
+	fn rfc1459_folds_brackets_and_tilde() {
+		assert_eq!(CaseMapping::Rfc1459.fold("NiCk[]\\~"), "nick{}|^");
+	}
+
+	#[test]
+	fn strict_rfc1459_keeps_tilde() {
+		assert_eq!(CaseMapping::StrictRfc1459.fold("NiCk[]\\~"), "nick{}|~");
+	}
+
+	#[test]
+	fn rfc7613_folds_unicode() {
+		assert_eq!(CaseMapping::Rfc7613.fold("ÉLISE"), "élise");
+		// a decomposed E with combining acute accent composes to the same name
+		assert_eq!(CaseMapping::Rfc7613.fold("E\u{301}lise"), "élise");
+		// fullwidth forms map to their ordinary counterparts
+		assert_eq!(CaseMapping::Rfc7613.fold("\u{ff22}\u{ff4f}\u{ff42}"), "bob");
+		// brackets are distinct characters under rfc7613
+		assert_eq!(CaseMapping::Rfc7613.fold("[a]"), "[a]");
+	}
+
+	#[test]
+	fn names_round_trip() {
+		for &mapping in &[CaseMapping::Ascii, CaseMapping::Rfc1459,
+				CaseMapping::StrictRfc1459, CaseMapping::Rfc7613] {
+			assert_eq!(CaseMapping::from_name(mapping.name()), Some(mapping));
+		}
+		assert_eq!(CaseMapping::from_name("unicode"), None);
+	}
+
+	#[test]
+	fn keys_compare_folded_and_display_as_given() {
+		let key = CaseMapping::Rfc1459.key("Bob[away]");
+		assert_eq!(key, CaseMapping::Rfc1459.key("bob{AWAY}"));
+		assert_eq!(key.as_str(), "Bob[away]");
+		assert_eq!(key.folded(), "bob{away}");
+	}
+}
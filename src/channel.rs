@@ -0,0 +1,604 @@
+use std::collections::{HashMap, HashSet};
+
+use casemap::{CaseMapping, CaseKey};
+use clock::unix_timestamp;
+use config::Config;
+use isupport::ISupport;
+use mask::{wildcard_match, is_wildcard, normalize_hostmask, hostmask_match};
+use mode::ModeChange;
+
+const MAX_CHANNEL_LEN: usize = 50;
+/// The most parameterised mode changes honoured in a single MODE command.
+pub const MAX_MODE_PARAMS: usize = 4;
+pub const CHANNEL_MODES: &str = "ISbeiklmnopstv";
+/// Modes holding a list of hostmasks: bans, ban exceptions, invite exceptions.
+pub const LIST_MODES: &str = "beI";
+/// Membership prefixes that may lead a channel name in PRIVMSG and NOTICE
+/// to reach only members of that rank.
+pub const STATUS_PREFIXES: &str = "@+";
+
+#[derive(Default)]
+pub struct Membership {
+	pub operator: bool,
+	pub voice: bool,
+}
+
+impl Membership {
+	/// The highest-ranking membership prefix, as shown in NAMES.
+	pub fn prefix(&self) -> &'static str {
+		if self.operator {
+			"@"
+		} else if self.voice {
+			"+"
+		} else {
+			""
+		}
+	}
+
+	/// Whether this member holds the rank a STATUSMSG prefix asks for.
+	pub fn has_status(&self, prefix: char) -> bool {
+		match prefix {
+			'@' => self.operator,
+			_ => self.operator || self.voice,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+	Banned,
+	InviteOnly,
+	BadKey,
+	Full,
+	SecureOnly,
+}
+
+#[derive(Debug)]
+pub enum ModeError {
+	UnknownMode(char),
+	UserNotInChannel(String),
+	ListFull(char),
+}
+
+pub struct ListEntry {
+	pub mask: String,
+	pub set_by: String,
+	pub set_at: u64,
+}
+
+pub struct Topic {
+	pub text: String,
+	pub set_by: String,
+	pub set_at: u64,
+}
+
+pub struct Channel {
+	pub name: String,
+	pub members: HashMap<CaseKey, Membership>, // nickname -> status
+	pub topic: Option<Topic>,
+	pub topic_locked: bool,
+	pub secret: bool,
+	pub private: bool,
+	pub moderated: bool,
+	pub no_external: bool,
+	pub invite_only: bool,
+	/// Only clients connected over TLS may join.
+	pub secure_only: bool,
+	pub key: Option<String>,
+	pub limit: Option<usize>,
+	pub created_at: u64,
+	pub invites: HashSet<CaseKey>, // nicknames invited but not yet joined
+	pub bans: Vec<ListEntry>,
+	pub excepts: Vec<ListEntry>,
+	pub invexes: Vec<ListEntry>,
+	pub casemapping: CaseMapping,
+}
+
+impl Channel {
+	pub fn new(name: String, casemapping: CaseMapping) -> Self {
+		Channel {
+			name,
+			members: HashMap::new(),
+			topic: None,
+			topic_locked: true,
+			secret: false,
+			private: false,
+			moderated: false,
+			no_external: true,
+			invite_only: false,
+			secure_only: false,
+			key: None,
+			limit: None,
+			created_at: unix_timestamp(),
+			invites: HashSet::new(),
+			bans: vec![],
+			excepts: vec![],
+			invexes: vec![],
+			casemapping}
+	}
+
+	pub fn is_member(&self, nick: &str) -> bool {
+		self.members.contains_key(self.casemapping.fold(nick).as_str())
+	}
+
+	pub fn is_operator(&self, nick: &str) -> bool {
+		self.members.get(self.casemapping.fold(nick).as_str()).is_some_and(|status| status.operator)
+	}
+
+	/// The first user into a channel is made its operator. Joining
+	/// consumes any pending invite.
+	pub fn add_member(&mut self, nick: &str) {
+		let founder = self.members.is_empty();
+		let key = self.casemapping.key(nick);
+		self.invites.remove(&key);
+		self.members.entry(key).or_insert(Membership {operator: founder, voice: false});
+	}
+
+	/// Let a nickname past +i on its next join.
+	pub fn invite(&mut self, nick: &str) {
+		self.invites.insert(self.casemapping.key(nick));
+	}
+
+	/// Voiced users and operators may still speak in a moderated channel,
+	/// and are not silenced by bans.
+	pub fn can_speak(&self, nick: &str, hostmask: &str) -> bool {
+		match self.members.get(self.casemapping.fold(nick).as_str()) {
+			Some(status) if status.operator || status.voice => true,
+			Some(_) => !self.moderated && !self.is_banned(hostmask),
+			None => !self.no_external && !self.moderated && !self.is_banned(hostmask),
+		}
+	}
+
+	/// Banned unless a ban exception also matches.
+	pub fn is_banned(&self, hostmask: &str) -> bool {
+		list_matches(&self.bans, hostmask, self.casemapping) && !list_matches(&self.excepts, hostmask, self.casemapping)
+	}
+
+	pub fn list(&self, mode: char) -> &Vec<ListEntry> {
+		match mode {
+			'b' => &self.bans,
+			'e' => &self.excepts,
+			_ => &self.invexes,
+		}
+	}
+
+	fn list_mut(&mut self, mode: char) -> &mut Vec<ListEntry> {
+		match mode {
+			'b' => &mut self.bans,
+			'e' => &mut self.excepts,
+			_ => &mut self.invexes,
+		}
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.limit.is_some_and(|limit| self.members.len() >= limit)
+	}
+
+	/// Check the +b, +S, +i, +k and +l restrictions for a user about to
+	/// join. A pending invite or matching invite exception lets the user
+	/// past +i, but not past +S.
+	pub fn admit(&self, nick: &str, hostmask: &str, key: Option<&String>, secure: bool) -> Result<(), JoinError> {
+		let invited = self.invites.contains(self.casemapping.fold(nick).as_str()) || list_matches(&self.invexes, hostmask, self.casemapping);
+		if self.is_banned(hostmask) {
+			Err(JoinError::Banned)
+		} else if self.secure_only && !secure {
+			Err(JoinError::SecureOnly)
+		} else if self.invite_only && !invited {
+			Err(JoinError::InviteOnly)
+		} else if self.key.is_some() && self.key.as_ref() != key {
+			Err(JoinError::BadKey)
+		} else if self.is_full() {
+			Err(JoinError::Full)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Carry a member's status and any pending invite over to a new nickname.
+	pub fn rename_member(&mut self, old_nick: &str, new_nick: &str) {
+		if let Some(status) = self.members.remove(self.casemapping.fold(old_nick).as_str()) {
+			self.members.insert(self.casemapping.key(new_nick), status);
+		}
+		if self.invites.remove(self.casemapping.fold(old_nick).as_str()) {
+			self.invites.insert(self.casemapping.key(new_nick));
+		}
+	}
+
+	pub fn remove_member(&mut self, nick: &str) -> bool {
+		self.members.remove(self.casemapping.fold(nick).as_str()).is_some()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.members.is_empty()
+	}
+
+	/// Secret and private channels are hidden from everyone but their members.
+	pub fn is_visible_to(&self, nick: &str) -> bool {
+		!(self.secret || self.private) || self.is_member(nick)
+	}
+
+	/// The channel type symbol used in RPL_NAMREPLY.
+	pub fn names_symbol(&self) -> char {
+		if self.secret {
+			'@'
+		} else if self.private {
+			'*'
+		} else {
+			'='
+		}
+	}
+
+	/// The current modes as sent in RPL_CHANNELMODEIS. The key is only
+	/// revealed to members.
+	pub fn mode_string(&self, show_key: bool) -> String {
+		let mut modes = String::from("+");
+		let mut params = vec![];
+		let flags = [
+			(self.secure_only, 'S'),
+			(self.invite_only, 'i'),
+			(self.moderated, 'm'),
+			(self.no_external, 'n'),
+			(self.private, 'p'),
+			(self.secret, 's'),
+			(self.topic_locked, 't')];
+		for &(set, mode) in flags.iter() {
+			if set {
+				modes.push(mode);
+			}
+		}
+		if let Some(ref key) = self.key {
+			modes.push('k');
+			params.push(if show_key { key.clone() } else { "*".to_string() });
+		}
+		if let Some(limit) = self.limit {
+			modes.push('l');
+			params.push(limit.to_string());
+		}
+		params.insert(0, modes);
+		params.join(" ")
+	}
+
+	/// Apply one mode change, returning the change as it should be
+	/// broadcast, or `Ok(None)` if it was a no-op or lacked its parameter.
+	/// `set_by` and `max_list_entries` apply to the +b, +e and +I lists.
+	pub fn apply_mode(&mut self, change: &ModeChange, set_by: &str, max_list_entries: usize)
+			-> Result<Option<ModeChange>, ModeError> {
+		let adding = change.adding;
+		let applied = match change.mode {
+			'b' | 'e' | 'I' => {
+				let mask = match change.param {
+					Some(ref mask) if !mask.is_empty() => normalize_hostmask(mask),
+					_ => { return Ok(None); },
+				};
+				let casemapping = self.casemapping;
+				let folded = casemapping.fold(&mask);
+				let list = self.list_mut(change.mode);
+				let position = list.iter().position(|entry| casemapping.fold(&entry.mask) == folded);
+				let entry_mask = match (adding, position) {
+					(true, None) if list.len() >= max_list_entries => {
+						return Err(ModeError::ListFull(change.mode));
+					},
+					(true, None) => {
+						list.push(ListEntry {mask: mask.clone(), set_by: set_by.to_string(), set_at: unix_timestamp()});
+						mask
+					},
+					(false, Some(ix)) => list.remove(ix).mask,
+					_ => { return Ok(None); },
+				};
+				return Ok(Some(ModeChange::new(adding, change.mode, Some(entry_mask))));
+			},
+			'o' | 'v' => {
+				let nick = match change.param {
+					Some(ref nick) => nick.clone(),
+					None => { return Ok(None); },
+				};
+				let status = match self.members.get_mut(self.casemapping.fold(&nick).as_str()) {
+					Some(status) => status,
+					None => { return Err(ModeError::UserNotInChannel(nick)); },
+				};
+				if change.mode == 'o' {
+					set_flag(&mut status.operator, adding)
+				} else {
+					set_flag(&mut status.voice, adding)
+				}
+			},
+			'k' => {
+				match (adding, change.param.as_ref()) {
+					(true, Some(key)) if !key.is_empty() => {
+						self.key = Some(key.clone());
+						true
+					},
+					(false, _) => self.key.take().is_some(),
+					_ => false,
+				}
+			},
+			'l' => {
+				match (adding, change.param.as_ref().and_then(|limit| limit.parse().ok())) {
+					(true, Some(limit)) if limit > 0 => {
+						self.limit = Some(limit);
+						true
+					},
+					(false, _) => self.limit.take().is_some(),
+					_ => false,
+				}
+			},
+			'i' => set_flag(&mut self.invite_only, adding),
+			'm' => set_flag(&mut self.moderated, adding),
+			'n' => set_flag(&mut self.no_external, adding),
+			'p' => set_flag(&mut self.private, adding),
+			's' => set_flag(&mut self.secret, adding),
+			't' => set_flag(&mut self.topic_locked, adding),
+			'S' => set_flag(&mut self.secure_only, adding),
+			other => { return Err(ModeError::UnknownMode(other)); },
+		};
+
+		if !applied {
+			return Ok(None);
+		}
+		let param = match change.mode {
+			'k' if !adding => Some("*".to_string()),
+			'l' if !adding => None,
+			_ => change.param.clone(),
+		};
+		Ok(Some(ModeChange::new(adding, change.mode, param)))
+	}
+
+	/// An empty text clears the topic.
+	pub fn set_topic(&mut self, text: String, set_by: String) {
+		if text.is_empty() {
+			self.topic = None;
+		} else {
+			self.topic = Some(Topic {text, set_by, set_at: unix_timestamp()});
+		}
+	}
+}
+
+/// Returns true if the flag actually changed.
+fn set_flag(flag: &mut bool, value: bool) -> bool {
+	let changed = *flag != value;
+	*flag = value;
+	changed
+}
+
+fn list_matches(list: &[ListEntry], hostmask: &str, casemapping: CaseMapping) -> bool {
+	list.iter().any(|entry| hostmask_match(&entry.mask, hostmask, casemapping))
+}
+
+/// Whether a channel mode change consumes a parameter. A key is accepted
+/// (and ignored) when unsetting, for compatibility with most clients. List
+/// modes without a parameter are a request to view the list.
+pub fn mode_takes_param(adding: bool, mode: char) -> bool {
+	match mode {
+		'o' | 'v' | 'k' | 'b' | 'e' | 'I' => true,
+		'l' => adding,
+		_ => false,
+	}
+}
+
+/// The channel masks and ELIST user count conditions accepted by LIST.
+#[derive(Default)]
+pub struct ListFilter {
+	masks: Vec<String>,
+	excluded_masks: Vec<String>,
+	min_users: Option<usize>,
+	max_users: Option<usize>,
+}
+
+impl ListFilter {
+	pub fn new(conditions: &[String]) -> Self {
+		let mut filter = ListFilter::default();
+		for condition in conditions {
+			if let Some(count) = condition.strip_prefix('>') {
+				filter.min_users = count.parse().ok();
+			} else if let Some(count) = condition.strip_prefix('<') {
+				filter.max_users = count.parse().ok();
+			} else if let Some(mask) = condition.strip_prefix('!') {
+				filter.excluded_masks.push(mask.to_string());
+			} else {
+				filter.masks.push(condition.to_string());
+			}
+		}
+		filter
+	}
+
+	pub fn matches(&self, channel: &Channel) -> bool {
+		let num_users = channel.members.len();
+		let casemapping = channel.casemapping;
+		let name = casemapping.fold(&channel.name);
+		let name_matches = |mask: &String| if is_wildcard(mask) {
+			wildcard_match(&casemapping.fold(mask), &name)
+		} else {
+			casemapping.fold(mask) == name
+		};
+
+		(self.masks.is_empty() || self.masks.iter().any(&name_matches))
+			&& !self.excluded_masks.iter().any(&name_matches)
+			&& self.min_users.is_none_or(|min| num_users > min)
+			&& self.max_users.is_none_or(|max| num_users < max)
+	}
+}
+
+/// Split a STATUSMSG target like `@#chan` into its prefix and channel.
+pub fn split_status_prefix(target: &str) -> (Option<char>, &str) {
+	match target.chars().next() {
+		Some(prefix) if STATUS_PREFIXES.contains(prefix) && is_channel_name(&target[1..]) =>
+			(Some(prefix), &target[1..]),
+		_ => (None, target),
+	}
+}
+
+pub fn isupport_tokens(config: &Config, isupport: &mut ISupport) {
+	isupport.add("CHANTYPES", "#&");
+	isupport.add("CHANMODES", "beI,k,l,Simnpst");
+	isupport.add("PREFIX", "(ov)@+");
+	isupport.add("STATUSMSG", STATUS_PREFIXES);
+	isupport.add("CHANNELLEN", &MAX_CHANNEL_LEN.to_string());
+	isupport.add("TOPICLEN", &config.max_topic_len.to_string());
+	isupport.add("MAXLIST", &format!("{}:{}", LIST_MODES, config.max_list_entries));
+	isupport.add("MODES", &MAX_MODE_PARAMS.to_string());
+	isupport.add("ELIST", "MNU");
+}
+
+pub fn is_channel_name(name: &str) -> bool {
+	name.starts_with('#') || name.starts_with('&')
+}
+
+pub fn is_valid_channel_name(name: &str) -> bool {
+	is_channel_name(name)
+		&& name.len() > 1
+		&& name.len() <= MAX_CHANNEL_LEN
+		&& !name.contains(&[' ', ',', '\x07', ':'][..])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BOB: &str = "bob!~b@host.example.com";
+
+	fn channel() -> Channel {
+		let mut channel = Channel::new("#test".to_string(), CaseMapping::Rfc1459);
+		channel.add_member("alice");
+		channel
+	}
+
+	fn set(channel: &mut Channel, adding: bool, mode: char, param: Option<&str>) -> Result<Option<ModeChange>, ModeError> {
+		let change = ModeChange::new(adding, mode, param.map(|param| param.to_string()));
+		channel.apply_mode(&change, "alice!~a@host", 2)
+	}
+
+	fn admit(channel: &Channel, key: Option<&str>, secure: bool) -> Result<(), JoinError> {
+		channel.admit("bob", BOB, key.map(|key| key.to_string()).as_ref(), secure)
+	}
+
+	#[test]
+	fn bans_keep_users_out_unless_excepted() {
+		let mut channel = channel();
+		assert!(admit(&channel, None, false).is_ok());
+		set(&mut channel, true, 'b', Some("*!*@*.example.com")).unwrap();
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::Banned)));
+		set(&mut channel, true, 'e', Some("bob")).unwrap();
+		assert!(admit(&channel, None, false).is_ok());
+	}
+
+	#[test]
+	fn invite_exceptions_do_not_lift_bans() {
+		let mut channel = channel();
+		set(&mut channel, true, 'b', Some("bob")).unwrap();
+		set(&mut channel, true, 'I', Some("bob")).unwrap();
+		channel.invite("bob");
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::Banned)));
+	}
+
+	#[test]
+	fn invites_and_invite_exceptions_pass_invite_only() {
+		let mut channel = channel();
+		set(&mut channel, true, 'i', None).unwrap();
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::InviteOnly)));
+		set(&mut channel, true, 'I', Some("*!*@host.example.com")).unwrap();
+		assert!(admit(&channel, None, false).is_ok());
+
+		let mut channel = self::channel();
+		set(&mut channel, true, 'i', None).unwrap();
+		channel.invite("BOB");
+		assert!(admit(&channel, None, false).is_ok());
+		// joining uses the invite up
+		channel.add_member("bob");
+		channel.remove_member("bob");
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::InviteOnly)));
+	}
+
+	#[test]
+	fn secure_only_comes_before_invites() {
+		let mut channel = channel();
+		set(&mut channel, true, 'S', None).unwrap();
+		set(&mut channel, true, 'i', None).unwrap();
+		channel.invite("bob");
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::SecureOnly)));
+		assert!(admit(&channel, None, true).is_ok());
+	}
+
+	#[test]
+	fn invite_only_comes_before_key_and_limit() {
+		let mut channel = channel();
+		set(&mut channel, true, 'i', None).unwrap();
+		set(&mut channel, true, 'k', Some("sesame")).unwrap();
+		set(&mut channel, true, 'l', Some("1")).unwrap();
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::InviteOnly)));
+		channel.invite("bob");
+		assert!(matches!(admit(&channel, None, false), Err(JoinError::BadKey)));
+		assert!(matches!(admit(&channel, Some("sesame"), false), Err(JoinError::Full)));
+		set(&mut channel, false, 'l', None).unwrap();
+		assert!(admit(&channel, Some("sesame"), false).is_ok());
+	}
+
+	#[test]
+	fn moderation_and_bans_silence_plain_members() {
+		let mut channel = channel();
+		channel.add_member("bob");
+		assert!(channel.can_speak("bob", BOB));
+		set(&mut channel, true, 'b', Some("bob")).unwrap();
+		assert!(!channel.can_speak("bob", BOB));
+		set(&mut channel, true, 'v', Some("bob")).unwrap();
+		assert!(channel.can_speak("bob", BOB));
+		set(&mut channel, false, 'v', Some("bob")).unwrap();
+		set(&mut channel, false, 'b', Some("bob")).unwrap();
+		set(&mut channel, true, 'm', None).unwrap();
+		assert!(!channel.can_speak("bob", BOB));
+		assert!(channel.can_speak("alice", "alice!~a@host"));
+	}
+
+	#[test]
+	fn outsiders_speak_only_without_no_external() {
+		let mut channel = channel();
+		assert!(!channel.can_speak("bob", BOB));
+		set(&mut channel, false, 'n', None).unwrap();
+		assert!(channel.can_speak("bob", BOB));
+		set(&mut channel, true, 'b', Some("bob")).unwrap();
+		assert!(!channel.can_speak("bob", BOB));
+	}
+
+	#[test]
+	fn lists_are_limited_and_deduplicated() {
+		let mut channel = channel();
+		let added = set(&mut channel, true, 'b', Some("[Bob]")).unwrap().unwrap();
+		assert_eq!(added.param, Some("[Bob]!*@*".to_string()));
+		// the same mask under the casemapping is already there
+		assert!(set(&mut channel, true, 'b', Some("{bob}!*@*")).unwrap().is_none());
+		set(&mut channel, true, 'b', Some("carol")).unwrap();
+		assert!(matches!(set(&mut channel, true, 'b', Some("dave")), Err(ModeError::ListFull('b'))));
+		// each list has its own limit
+		assert!(set(&mut channel, true, 'e', Some("dave")).unwrap().is_some());
+
+		let removed = set(&mut channel, false, 'b', Some("{BOB}")).unwrap().unwrap();
+		assert_eq!(removed.param, Some("[Bob]!*@*".to_string()));
+		assert!(set(&mut channel, false, 'b', Some("bob")).unwrap().is_none());
+		assert_eq!(channel.list('b').len(), 1);
+	}
+
+	#[test]
+	fn unsetting_the_key_broadcasts_a_star() {
+		let mut channel = channel();
+		let set_key = set(&mut channel, true, 'k', Some("sesame")).unwrap().unwrap();
+		assert_eq!(set_key.param, Some("sesame".to_string()));
+		assert_eq!(channel.mode_string(true), "+ntk sesame");
+		assert_eq!(channel.mode_string(false), "+ntk *");
+		let unset_key = set(&mut channel, false, 'k', Some("whatever")).unwrap().unwrap();
+		assert_eq!(unset_key.param, Some("*".to_string()));
+		assert_eq!(channel.key, None);
+		assert!(set(&mut channel, false, 'k', None).unwrap().is_none());
+	}
+
+	#[test]
+	fn mode_changes_report_what_changed() {
+		let mut channel = channel();
+		assert!(set(&mut channel, true, 'n', None).unwrap().is_none());
+		assert!(set(&mut channel, true, 'l', Some("0")).unwrap().is_none());
+		assert!(matches!(set(&mut channel, true, 'o', Some("carol")), Err(ModeError::UserNotInChannel(_))));
+		assert!(matches!(set(&mut channel, true, 'z', None), Err(ModeError::UnknownMode('z'))));
+		let limit = set(&mut channel, false, 'l', None).unwrap();
+		assert!(limit.is_none());
+		set(&mut channel, true, 'l', Some("5")).unwrap();
+		assert_eq!(set(&mut channel, false, 'l', None).unwrap().unwrap().param, None);
+	}
+}
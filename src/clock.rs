@@ -0,0 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the unix epoch, as used in numeric replies.
+pub fn unix_timestamp() -> u64 {
+	match SystemTime::now().duration_since(UNIX_EPOCH) {
+		Ok(elapsed) => elapsed.as_secs(),
+		Err(_) => 0,
+	}
+}
+
+/// Render a unix timestamp as e.g. `Sat Oct 17 2026 -- 09:41:07 UTC`.
+pub fn format_timestamp(timestamp: u64) -> String {
+	const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+	const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+		"Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+	let days = timestamp / 86400;
+	let seconds = timestamp % 86400;
+	// civil date from days since the epoch, after Howard Hinnant's algorithm
+	let z = days as i64 + 719468;
+	let era = z.div_euclid(146097);
+	let day_of_era = z.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let shifted_month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+	let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!("{} {} {} {} -- {:02}:{:02}:{:02} UTC",
+		WEEKDAYS[(days % 7) as usize],
+		MONTHS[(month - 1) as usize],
+		day,
+		year,
+		seconds / 3600,
+		seconds / 60 % 60,
+		seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn formats_the_epoch() {
+		assert_eq!(format_timestamp(0), "Thu Jan 1 1970 -- 00:00:00 UTC");
+	}
+
+	#[test]
+	fn formats_leap_days() {
+		assert_eq!(format_timestamp(951782400), "Tue Feb 29 2000 -- 00:00:00 UTC");
+		assert_eq!(format_timestamp(1709251199), "Thu Feb 29 2024 -- 23:59:59 UTC");
+		// 2100 is not a leap year
+		assert_eq!(format_timestamp(4107542400), "Mon Mar 1 2100 -- 00:00:00 UTC");
+	}
+
+	#[test]
+	fn formats_time_of_day() {
+		assert_eq!(format_timestamp(1791884467), "Tue Oct 13 2026 -- 09:41:07 UTC");
+	}
+}
@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use toml;
+
+use oper::{Operator};
+use casemap::{CaseMapping};
+use mask::{wildcard_match};
+
+/// The class connections are placed in unless configured otherwise.
+pub const DEFAULT_CLASS: &str = "default";
+
+/// Limits shared by a group of connections.
+#[derive(Clone, Debug)]
+pub struct ConnectionClass {
+	pub name: String,
+	/// The most bytes queued for a client before it is disconnected.
+	pub max_sendq: usize,
+	/// The most bytes a client may have sent that are still waiting to be
+	/// processed before it is disconnected for flooding.
+	pub max_recvq: usize,
+	/// How far ahead of real time, in milliseconds, the flood penalty of a
+	/// client may run before its commands are held back.
+	pub flood_burst_ms: u64,
+	/// The flood penalty of a command, in milliseconds.
+	pub default_command_cost_ms: u64,
+	/// Commands whose penalty differs from the default, by name.
+	pub command_costs_ms: HashMap<String, u64>,
+	/// How many seconds a client may stay silent before it is sent a PING,
+	/// and then how many more it has to answer before it is disconnected.
+	pub ping_frequency_secs: u64,
+}
+
+impl ConnectionClass {
+	pub fn command_cost_ms(&self, command: &str) -> u64 {
+		self.command_costs_ms.get(command).cloned().unwrap_or(self.default_command_cost_ms)
+	}
+}
+
+impl Default for ConnectionClass {
+	fn default() -> Self {
+		// replies to these are large, so they cost more
+		let command_costs_ms = [("JOIN", 2000), ("NAMES", 2000), ("LIST", 3000), ("WHO", 2000),
+			("WHOIS", 1500), ("WHOWAS", 1500), ("PONG", 0)].iter()
+			.map(|&(command, cost)| (command.to_string(), cost))
+			.collect();
+		ConnectionClass {
+			name: DEFAULT_CLASS.to_string(),
+			max_sendq: 1024 * 1024,
+			max_recvq: 8192,
+			flood_burst_ms: 10_000,
+			default_command_cost_ms: 1000,
+			command_costs_ms,
+			ping_frequency_secs: 120}
+	}
+}
+
+/// An address and port on which clients are accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+	pub address: SocketAddr,
+	/// Clients must connect with TLS.
+	pub tls: bool,
+	/// The connection class clients accepted here are placed in.
+	pub class: String,
+}
+
+impl Listener {
+	pub fn new(address: SocketAddr, tls: bool) -> Self {
+		Listener {address, tls, class: DEFAULT_CLASS.to_string()}
+	}
+}
+
+/// Contact details sent in reply to ADMIN.
+#[derive(Clone, Debug, Default)]
+pub struct AdminInfo {
+	pub location: String,
+	pub details: String,
+	pub email: String,
+}
+
+/// A ban on clients connecting as a `user@host` mask, such as
+/// `*@203.0.113.*`. Clients are checked when they register, and again
+/// whenever the configuration is reloaded.
+#[derive(Clone, Debug)]
+pub struct Kline {
+	pub mask: String,
+	pub reason: String,
+}
+
+impl Kline {
+	pub fn matches(&self, user: &str, host: &str) -> bool {
+		wildcard_match(&self.mask, &format!("{}@{}", user, host))
+	}
+}
+
+/// The configuration in use, which REHASH may swap for a new one. Take a
+/// clone of the inner `Arc` rather than holding the lock.
+pub type SharedConfig = Arc<Mutex<Arc<Config>>>;
+
+/// Builds the configuration afresh for REHASH, such as by reading the file
+/// it came from again.
+pub type ConfigLoader = Arc<dyn Fn() -> Result<Config, String> + Send + Sync>;
+
+/// Server settings. REHASH can replace all but the server name, listeners
+/// and casemapping while the server runs.
+#[derive(Clone)]
+pub struct Config {
+	/// The file these settings were read from, if any.
+	pub path: Option<String>,
+	/// How the server identifies itself to clients.
+	pub server_name: String,
+	pub listeners: Vec<Listener>,
+	/// PEM files holding the certificate chain and private key for TLS
+	/// listeners.
+	pub tls_certificate: String,
+	pub tls_private_key: String,
+	/// Advertised to clients as NETWORK.
+	pub network: String,
+	/// Shown in WHOIS replies.
+	pub description: String,
+	pub admin: AdminInfo,
+	/// The file sent as the message of the day.
+	pub motd_path: String,
+	/// Required from clients with PASS before they may register.
+	pub password: Option<String>,
+	pub operators: Vec<Operator>,
+	/// Clients matching any of these are disconnected.
+	pub klines: Vec<Kline>,
+	/// The most entries allowed in each of a channel's +b, +e and +I lists.
+	pub max_list_entries: usize,
+	/// How many departed nicknames WHOWAS remembers.
+	pub whowas_length: usize,
+	/// The longest nickname accepted from NICK.
+	pub max_nick_len: usize,
+	/// How nicknames and channel names are compared.
+	pub casemapping: CaseMapping,
+	/// Longer topics, away messages and kick comments are cut short.
+	pub max_topic_len: usize,
+	pub max_away_len: usize,
+	pub max_kick_len: usize,
+	/// How many seconds a connection has to complete NICK and USER.
+	pub registration_timeout_secs: u64,
+	/// The default class always comes first.
+	pub classes: Vec<ConnectionClass>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			path: None,
+			server_name: "localhost".to_string(),
+			listeners: vec![Listener::new(SocketAddr::from(([127, 0, 0, 1], 6667)), false)],
+			tls_certificate: "cert.pem".to_string(),
+			tls_private_key: "key.pem".to_string(),
+			network: "rustirc".to_string(),
+			description: "A rustirc server".to_string(),
+			admin: AdminInfo::default(),
+			motd_path: "motd.txt".to_string(),
+			password: None,
+			operators: vec![],
+			klines: vec![],
+			max_list_entries: 50,
+			whowas_length: 100,
+			max_nick_len: 30,
+			casemapping: CaseMapping::default(),
+			max_topic_len: 390,
+			max_away_len: 200,
+			max_kick_len: 255,
+			registration_timeout_secs: 60,
+			classes: vec![ConnectionClass::default()]}
+	}
+}
+
+impl Config {
+	/// Read a TOML configuration file. Anything it leaves out keeps its
+	/// default, and relative paths in it are taken from the file's own
+	/// directory.
+	pub fn load(path: &str) -> Result<Config, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+		let file : ConfigFile = toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+		let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+		let mut config = file.into_config(directory).map_err(|e| format!("{}: {}", path, e))?;
+		config.path = Some(path.to_string());
+		Ok(config)
+	}
+
+	/// The named connection class, or the built-in defaults if there is none.
+	pub fn class(&self, name: &str) -> ConnectionClass {
+		self.classes.iter()
+			.find(|class| class.name == name)
+			.cloned()
+			.unwrap_or_default()
+	}
+}
+
+// The layout of the configuration file. Every section and most settings
+// are optional; misspelt ones are rejected rather than ignored.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	#[serde(default)]
+	server: ServerSection,
+	#[serde(default)]
+	admin: AdminSection,
+	#[serde(default)]
+	limits: LimitsSection,
+	tls: Option<TlsSection>,
+	#[serde(default, rename = "listener")]
+	listeners: Vec<ListenerSection>,
+	#[serde(default, rename = "class")]
+	classes: Vec<ClassSection>,
+	#[serde(default, rename = "operator")]
+	operators: Vec<OperatorSection>,
+	#[serde(default, rename = "kline")]
+	klines: Vec<KlineSection>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+	name: Option<String>,
+	network: Option<String>,
+	description: Option<String>,
+	motd: Option<String>,
+	password: Option<String>,
+	casemapping: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AdminSection {
+	location: Option<String>,
+	details: Option<String>,
+	email: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+	nick_length: Option<usize>,
+	topic_length: Option<usize>,
+	away_length: Option<usize>,
+	kick_length: Option<usize>,
+	list_entries: Option<usize>,
+	whowas: Option<usize>,
+	registration_timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+	certificate: String,
+	private_key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerSection {
+	address: String,
+	#[serde(default)]
+	tls: bool,
+	class: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassSection {
+	name: String,
+	sendq: Option<usize>,
+	recvq: Option<usize>,
+	ping_frequency: Option<u64>,
+	flood_burst: Option<u64>,
+	command_cost: Option<u64>,
+	#[serde(default)]
+	command_costs: HashMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OperatorSection {
+	name: String,
+	password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KlineSection {
+	mask: String,
+	reason: Option<String>,
+}
+
+impl ConfigFile {
+	fn into_config(self, directory: &Path) -> Result<Config, String> {
+		let mut config = Config::default();
+		let resolve = |path: &str| directory.join(path).to_string_lossy().into_owned();
+
+		let server = self.server;
+		if let Some(name) = server.name {
+			if !is_valid_server_name(&name) {
+				return Err(format!("server.name \"{}\" must be a host name: letters, digits, '-' and '.'", name));
+			}
+			config.server_name = name;
+		}
+		if let Some(network) = server.network {
+			if network.is_empty() || network.contains(' ') {
+				return Err(format!("server.network \"{}\" must be a single word", network));
+			}
+			config.network = network;
+		}
+		if let Some(description) = server.description {
+			config.description = description;
+		}
+		if let Some(motd) = server.motd {
+			let motd = resolve(&motd);
+			if !Path::new(&motd).is_file() {
+				return Err(format!("server.motd: {} is not a file", motd));
+			}
+			config.motd_path = motd;
+		}
+		config.password = server.password;
+		if let Some(name) = server.casemapping {
+			config.casemapping = CaseMapping::from_name(&name).ok_or_else(|| format!(
+				"server.casemapping \"{}\" must be ascii, rfc1459, strict-rfc1459 or rfc7613", name))?;
+		}
+
+		let admin = self.admin;
+		config.admin = AdminInfo {
+			location: admin.location.unwrap_or_default(),
+			details: admin.details.unwrap_or_default(),
+			email: admin.email.unwrap_or_default()};
+
+		let limits = self.limits;
+		let lengths = [
+			("nick_length", limits.nick_length, &mut config.max_nick_len),
+			("topic_length", limits.topic_length, &mut config.max_topic_len),
+			("away_length", limits.away_length, &mut config.max_away_len),
+			("kick_length", limits.kick_length, &mut config.max_kick_len)];
+		for (name, value, setting) in lengths {
+			match value {
+				Some(0) => { return Err(format!("limits.{} must be at least 1", name)); },
+				Some(value) => { *setting = value; },
+				None => {},
+			}
+		}
+		if let Some(list_entries) = limits.list_entries {
+			config.max_list_entries = list_entries;
+		}
+		if let Some(whowas) = limits.whowas {
+			config.whowas_length = whowas;
+		}
+		match limits.registration_timeout {
+			Some(0) => { return Err("limits.registration_timeout must be at least 1".to_string()); },
+			Some(timeout) => { config.registration_timeout_secs = timeout; },
+			None => {},
+		}
+
+		let has_tls = self.tls.is_some();
+		if let Some(tls) = self.tls {
+			config.tls_certificate = resolve(&tls.certificate);
+			config.tls_private_key = resolve(&tls.private_key);
+		}
+
+		let mut classes : Vec<ConnectionClass> = vec![];
+		for section in self.classes {
+			if classes.iter().any(|class| class.name == section.name) {
+				return Err(format!("class \"{}\" is defined twice", section.name));
+			}
+			classes.push(section.into_class()?);
+		}
+		// the default class goes first, built in if it was not configured
+		match classes.iter().position(|class| class.name == DEFAULT_CLASS) {
+			Some(ix) => {
+				let default = classes.remove(ix);
+				classes.insert(0, default);
+			},
+			None => { classes.insert(0, ConnectionClass::default()); },
+		}
+
+		if !self.listeners.is_empty() {
+			config.listeners = vec![];
+		}
+		for section in self.listeners {
+			let address = section.address.parse::<SocketAddr>().map_err(|_| format!(
+				"listener \"{}\" must be ADDRESS:PORT, such as 0.0.0.0:6667 or [::]:6667", section.address))?;
+			if section.tls && !has_tls {
+				return Err(format!("listener {} uses TLS but there is no [tls] section", address));
+			}
+			let mut listener = Listener::new(address, section.tls);
+			if let Some(class) = section.class {
+				if !classes.iter().any(|defined| defined.name == class) {
+					return Err(format!("listener {} names class \"{}\", which is not defined", address, class));
+				}
+				listener.class = class;
+			}
+			config.listeners.push(listener);
+		}
+		config.classes = classes;
+
+		for section in self.operators {
+			if section.name.is_empty() || section.name.contains(' ') {
+				return Err(format!("operator \"{}\" must be named with a single word", section.name));
+			}
+			if section.password.is_empty() {
+				return Err(format!("operator \"{}\" has an empty password", section.name));
+			}
+			config.operators.push(Operator::new(section.name, section.password));
+		}
+
+		for section in self.klines {
+			if section.mask.is_empty() || section.mask.contains(' ') || section.mask.contains('!') {
+				return Err(format!("kline \"{}\" must be a USER@HOST mask", section.mask));
+			}
+			// a bare host bans every user on it
+			let mask = if section.mask.contains('@') {
+				section.mask
+			} else {
+				format!("*@{}", section.mask)
+			};
+			config.klines.push(Kline {mask, reason: section.reason.unwrap_or_else(|| "K-lined".to_string())});
+		}
+		Ok(config)
+	}
+}
+
+impl ClassSection {
+	fn into_class(self) -> Result<ConnectionClass, String> {
+		let mut class = ConnectionClass {name: self.name, ..ConnectionClass::default()};
+		if let Some(sendq) = self.sendq {
+			if sendq == 0 {
+				return Err(format!("class \"{}\": sendq must be at least 1", class.name));
+			}
+			class.max_sendq = sendq;
+		}
+		if let Some(recvq) = self.recvq {
+			// it must at least hold one full line
+			if recvq < 512 {
+				return Err(format!("class \"{}\": recvq must be at least 512", class.name));
+			}
+			class.max_recvq = recvq;
+		}
+		if let Some(ping_frequency) = self.ping_frequency {
+			if ping_frequency == 0 {
+				return Err(format!("class \"{}\": ping_frequency must be at least 1", class.name));
+			}
+			class.ping_frequency_secs = ping_frequency;
+		}
+		if let Some(flood_burst) = self.flood_burst {
+			class.flood_burst_ms = flood_burst;
+		}
+		if let Some(command_cost) = self.command_cost {
+			class.default_command_cost_ms = command_cost;
+		}
+		for (command, cost) in self.command_costs {
+			class.command_costs_ms.insert(command.to_uppercase(), cost);
+		}
+		Ok(class)
+	}
+}
+
+/// A server name is a host name: letters, digits, hyphens and dots.
+fn is_valid_server_name(name: &str) -> bool {
+	!name.is_empty() && name.len() <= 63
+		&& !name.starts_with('.') && !name.starts_with('-')
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::process;
+
+	/// Load `text` as if it were a configuration file called `name`.
+	fn load(name: &str, text: &str) -> Result<Config, String> {
+		let path = env::temp_dir().join(format!("rustirc-test-{}-{}.toml", process::id(), name));
+		fs::write(&path, text).unwrap();
+		let config = Config::load(path.to_str().unwrap());
+		fs::remove_file(&path).unwrap();
+		config
+	}
+
+	fn load_err(name: &str, text: &str) -> String {
+		match load(name, text) {
+			Ok(_) => panic!("{} loaded", name),
+			Err(e) => e,
+		}
+	}
+
+	#[test]
+	fn empty_file_gives_defaults() {
+		let config = load("empty", "").unwrap();
+		assert_eq!(config.server_name, "localhost");
+		assert_eq!(config.listeners, Config::default().listeners);
+		assert_eq!(config.classes.len(), 1);
+		assert_eq!(config.classes[0].name, DEFAULT_CLASS);
+		assert!(config.path.unwrap().ends_with("empty.toml"));
+	}
+
+	#[test]
+	fn reads_settings_and_resolves_paths() {
+		let config = load("full", "
+			[server]
+			name = \"irc.example.net\"
+			casemapping = \"rfc7613\"
+			[limits]
+			nick_length = 16
+			[tls]
+			certificate = \"cert.pem\"
+			private_key = \"key.pem\"
+			[[listener]]
+			address = \"[::]:6697\"
+			tls = true
+			class = \"secure\"
+			[[class]]
+			name = \"secure\"
+			sendq = 4096
+			[[class]]
+			name = \"default\"
+			ping_frequency = 30
+			[[kline]]
+			mask = \"203.0.113.*\"
+		").unwrap();
+		assert_eq!(config.server_name, "irc.example.net");
+		assert_eq!(config.casemapping, CaseMapping::Rfc7613);
+		assert_eq!(config.max_nick_len, 16);
+		assert_eq!(Path::new(&config.tls_certificate), env::temp_dir().join("cert.pem"));
+		assert_eq!(config.listeners.len(), 1);
+		assert!(config.listeners[0].tls);
+		assert_eq!(config.listeners[0].class, "secure");
+		// the default class is moved to the front
+		assert_eq!(config.classes[0].name, DEFAULT_CLASS);
+		assert_eq!(config.classes[0].ping_frequency_secs, 30);
+		assert_eq!(config.class("secure").max_sendq, 4096);
+		assert_eq!(config.class("missing").name, DEFAULT_CLASS);
+		assert_eq!(config.klines[0].mask, "*@203.0.113.*");
+		assert!(config.klines[0].matches("bob", "203.0.113.7"));
+	}
+
+	#[test]
+	fn rejects_malformed_toml() {
+		let e = load_err("syntax", "[server\nname = 1");
+		assert!(e.contains("syntax.toml"), "{}", e);
+	}
+
+	#[test]
+	fn rejects_unknown_settings() {
+		let e = load_err("unknown", "[server]\nnmae = \"irc.example.net\"");
+		assert!(e.contains("nmae"), "{}", e);
+	}
+
+	#[test]
+	fn rejects_missing_file() {
+		let path = env::temp_dir().join(format!("rustirc-test-{}-missing.toml", process::id()));
+		assert!(Config::load(path.to_str().unwrap()).is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_values() {
+		let cases = [
+			("name", "[server]\nname = \"irc example\"", "server.name \"irc example\" must be a host name"),
+			("network", "[server]\nnetwork = \"\"", "server.network \"\" must be a single word"),
+			("motd", "[server]\nmotd = \"no-such-motd.txt\"", "is not a file"),
+			("casemapping", "[server]\ncasemapping = \"unicode\"", "server.casemapping \"unicode\" must be"),
+			("nicklen", "[limits]\nnick_length = 0", "limits.nick_length must be at least 1"),
+			("regtimeout", "[limits]\nregistration_timeout = 0", "limits.registration_timeout must be at least 1"),
+			("address", "[[listener]]\naddress = \"localhost\"", "listener \"localhost\" must be ADDRESS:PORT"),
+			("notls", "[[listener]]\naddress = \"0.0.0.0:6697\"\ntls = true",
+				"listener 0.0.0.0:6697 uses TLS but there is no [tls] section"),
+			("noclass", "[[listener]]\naddress = \"0.0.0.0:6667\"\nclass = \"vip\"",
+				"listener 0.0.0.0:6667 names class \"vip\", which is not defined"),
+			("twice", "[[class]]\nname = \"vip\"\n[[class]]\nname = \"vip\"", "class \"vip\" is defined twice"),
+			("sendq", "[[class]]\nname = \"vip\"\nsendq = 0", "class \"vip\": sendq must be at least 1"),
+			("recvq", "[[class]]\nname = \"vip\"\nrecvq = 511", "class \"vip\": recvq must be at least 512"),
+			("ping", "[[class]]\nname = \"vip\"\nping_frequency = 0", "class \"vip\": ping_frequency must be at least 1"),
+			("opername", "[[operator]]\nname = \"the boss\"\npassword = \"x\"", "operator \"the boss\" must be named with a single word"),
+			("operpass", "[[operator]]\nname = \"boss\"\npassword = \"\"", "operator \"boss\" has an empty password"),
+			("kline", "[[kline]]\nmask = \"bob!*@*\"", "kline \"bob!*@*\" must be a USER@HOST mask"),
+		];
+		for &(name, text, expected) in &cases {
+			let e = load_err(name, text);
+			assert!(e.contains(expected), "{}: {}", name, e);
+		}
+	}
+}
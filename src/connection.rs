@@ -1,132 +1,532 @@
-use std::net::{TcpStream, SocketAddr};
-use std::io::{Write, BufRead};
-use bufstream::BufStream;
+use std::net::{IpAddr, SocketAddr};
+use std::io::{self, BufRead, BufReader, Read};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::prelude::*;
+use std::time::{Duration, Instant};
 use std::str;
 
-use parser::{Command, User, parse_message};
+use parser::{Command, User, USER_MODES, parse_message, command_name, is_valid_nickname};
+use channel::{Channel, JoinError, ListFilter, ModeError, MAX_MODE_PARAMS, CHANNEL_MODES, LIST_MODES,
+	is_channel_name, is_valid_channel_name, mode_takes_param, split_status_prefix};
+use mode::{parse_mode_changes, format_mode_changes};
+use config::{Config, ConnectionClass, SharedConfig};
+use flood::{FloodControl};
+use server::{SharedState};
+use mask::{wildcard_match};
+use clock::{unix_timestamp, format_timestamp};
+use whowas::{WhowasHistory, WhowasEntry};
+use oper::{PasswordCheck, PasswordChecker};
+use sendq::{SendQueue, LinkStats};
+
+use mio::{Interest, Registry, Token};
+use mio::event::Source;
+use casemap::{CaseKey};
+use isupport::{ISupport};
+use tls::{Transport};
+use rehash::{Rehasher};
+
+// leaves room for the prefix and channel name within the 512 byte line limit
+const MAX_NAMES_LEN: usize = 400;
+// sent in RPL_YOURHOST and RPL_MYINFO
+const VERSION: &str = concat!("rustirc-", env!("CARGO_PKG_VERSION"));
+// WHOX fields in the order they must be sent
+const WHOX_FIELDS: &str = "tcuihsnfdlaor";
+
+/// Where a connection is in registration. A client that opens with CAP LS
+/// is held back from registering until it sends CAP END.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Registration {
+	Unregistered,
+	Negotiating,
+	Registered,
+}
+
+/// What a password being checked on the worker thread was given for.
+enum Verifying {
+	Registration,
+	/// The name of the operator block.
+	Oper(String),
+}
+
+struct WhoEntry {
+	channel: String,
+	nick: String,
+	user: User,
+	addr: SocketAddr,
+	prefix: &'static str,
+}
 
 pub struct Connection {
+	registration: Registration,
+	/// Set once the connection should be dropped.
+	closing: bool,
+	/// Sent with PASS, checked against the server password on registration.
+	password: Option<String>,
+	/// A password check whose result has not come back yet. Until it does,
+	/// further lines wait in the RecvQ.
+	verifying: Option<(Verifying, Receiver<bool>)>,
 	my_nickname: Option<String>,
-	nicknames: Arc<Mutex<HashMap<String, SocketAddr>>>,
+	nicknames: Arc<Mutex<HashMap<CaseKey, SocketAddr>>>,
 	users: Arc<Mutex<HashMap<SocketAddr, User>>>,
-	local_addr: SocketAddr,
+	channels: Arc<Mutex<HashMap<CaseKey, Channel>>>,
 	peer_addr: SocketAddr,
-	stream: BufStream<TcpStream>,
-	rx: mpsc::Receiver<String>,
-	phonebook: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<String>>>>,
+	stream: Transport,
+	/// The RecvQ: bytes read from the client and not yet acted on.
+	recv_buffer: Vec<u8>,
+	class: ConnectionClass,
+	flood: FloodControl,
+	connected_at: Instant,
+	/// When the client last sent anything.
+	last_heard: Instant,
+	/// When the server sent a PING that has not been answered yet.
+	ping_sent: Option<Instant>,
+	sendq: SendQueue,
+	/// Whether the reactor is watching for the socket to become writable.
+	awaiting_writable: bool,
+	phonebook: Arc<Mutex<HashMap<SocketAddr, SendQueue>>>,
 	num_known_users: Arc<Mutex<usize>>,
+	whowas: Arc<Mutex<WhowasHistory>>,
+	config: SharedConfig,
+	password_checker: PasswordChecker,
+	started_at: u64,
+	rehasher: Rehasher,
 }
 
 impl Connection {
-	pub fn new(stream: TcpStream,
-		nicknames: Arc<Mutex<HashMap<String, SocketAddr>>>,
-		users: Arc<Mutex<HashMap<SocketAddr, User>>>,
-		rx: mpsc::Receiver<String>,
-		phonebook: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<String>>>>,
-		num_known_users: Arc<Mutex<usize>>) -> Self {
+	pub fn new(stream: Transport, peer_addr: SocketAddr,
+			class: ConnectionClass, sendq: SendQueue, state: SharedState) -> Self {
 		Connection {
+			registration: Registration::Unregistered,
+			closing: false,
+			password: None,
+			verifying: None,
 			my_nickname: None,
-			nicknames: nicknames,
-			users: users,
-			local_addr: stream.local_addr().unwrap(),
-			peer_addr: stream.peer_addr().unwrap(),
-			stream: BufStream::new(stream),
-			rx: rx,
-			phonebook: phonebook,
-			num_known_users: num_known_users}
+			nicknames: state.nicknames,
+			users: state.users,
+			channels: state.channels,
+			peer_addr,
+			stream,
+			recv_buffer: vec![],
+			flood: FloodControl::new(Duration::from_millis(class.flood_burst_ms)),
+			class,
+			connected_at: Instant::now(),
+			last_heard: Instant::now(),
+			ping_sent: None,
+			sendq,
+			awaiting_writable: false,
+			phonebook: state.phonebook,
+			num_known_users: state.num_known_users,
+			whowas: state.whowas,
+			config: state.config,
+			password_checker: state.password_checker,
+			started_at: state.started_at,
+			rehasher: state.rehasher}
 	}
 
-	pub fn handle_client(&mut self) {
-		loop {
-			if let Ok(message) = self.rx.try_recv() {
-				self.write_reply(format!("{}\r\n", message));
+	/// Read whatever the client has sent into the RecvQ and act on the
+	/// complete lines. The socket is edge-triggered, so this reads until it
+	/// would block. A client that sends more than its RecvQ can hold, even
+	/// after flood control has let through what it can, is disconnected.
+	pub fn handle_readable(&mut self) {
+		let mut chunk = [0; 4096];
+		let mut lost = None;
+		while !self.closing {
+			match self.stream.read(&mut chunk) {
+				Ok(0) => {
+					lost = Some("Connection closed".to_string());
+					break;
+				},
+				Ok(n) => {
+					// anything at all from the client shows it is still there
+					self.last_heard = Instant::now();
+					self.ping_sent = None;
+					self.recv_buffer.extend_from_slice(&chunk[..n]);
+					if self.recv_buffer.len() > self.class.max_recvq {
+						self.process_lines();
+						if self.recv_buffer.len() > self.class.max_recvq {
+							self.disconnect("Excess Flood".to_string());
+						}
+					}
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { break; },
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+				Err(e) => {
+					error!("Stream Read Error: {}", e);
+					lost = Some(format!("Read error: {}", e));
+					break;
+				},
 			}
+		}
 
-			let mut buffer = String::new();
+		// whatever arrived before the connection was lost still counts
+		self.process_lines();
+		if let Some(reason) = lost {
+			self.disconnect(reason);
+		}
+	}
 
-			if let Err(_) = self.stream.read_line(&mut buffer) {
-				// error!("Stream Read Error: {}", e);
-				continue;
+	/// Act on the complete lines in the RecvQ for as long as flood control
+	/// allows, and no password is being checked. Operators are exempt from
+	/// flood control.
+	pub fn process_lines(&mut self) {
+		while !self.closing && self.verifying.is_none() {
+			let end = match self.recv_buffer.iter().position(|&c| c == b'\n') {
+				Some(end) => end,
+				None => { break; },
+			};
+			let now = Instant::now();
+			if !self.flood.is_ready(now) && !self.is_operator() {
+				break;
+			}
+			let line : Vec<u8> = self.recv_buffer.drain(..=end).collect();
+			self.sendq.record_received(1, line.len() as u64);
+			let line = String::from_utf8_lossy(&line).into_owned();
+			// empty lines are silently ignored
+			if !line.trim_end().is_empty() {
+				let cost = self.class.command_cost_ms(&command_name(&line).to_uppercase());
+				self.flood.charge(now, Duration::from_millis(cost));
+				self.handle_line(line);
+			}
+		}
+	}
+
+	/// Act on whatever has come due: lines flood control was holding back,
+	/// a quiet client to PING, or a client that has run out of time to
+	/// register or to answer a PING.
+	pub fn handle_timeout(&mut self) {
+		self.process_lines();
+		if self.closing {
+			return;
+		}
+		let now = Instant::now();
+		if self.registration != Registration::Registered {
+			if now >= self.registration_deadline() {
+				self.disconnect("Registration timed out".to_string());
+			}
+		} else if self.ping_sent.is_some() {
+			if now >= self.ping_deadline() {
+				let silence = now.duration_since(self.last_heard).as_secs();
+				self.disconnect(format!("Ping timeout: {} seconds", silence));
+			}
+		} else if now >= self.ping_deadline() {
+			let ping = format!("PING :{}\r\n", self.config().server_name);
+			self.write_reply(ping);
+			self.ping_sent = Some(now);
+		}
+	}
+
+	/// When the reactor should come back to this connection even if its
+	/// socket stays quiet: as soon as flood control lets held back lines
+	/// through, or when it is next due to be PINGed or timed out. Lines held
+	/// back for a password check are seen to when its result comes back.
+	pub fn wakeup_at(&self) -> Option<Instant> {
+		if self.closing {
+			return None;
+		}
+		let deadline = if self.registration != Registration::Registered {
+			self.registration_deadline()
+		} else {
+			self.ping_deadline()
+		};
+		if self.verifying.is_none() && self.recv_buffer.contains(&b'\n') {
+			Some(deadline.min(self.flood.ready_at()))
+		} else {
+			Some(deadline)
+		}
+	}
+
+	fn registration_deadline(&self) -> Instant {
+		self.connected_at + Duration::from_secs(self.config().registration_timeout_secs)
+	}
+
+	/// When a quiet client is sent a PING, or, once it has been, when it is
+	/// disconnected for not answering.
+	fn ping_deadline(&self) -> Instant {
+		let frequency = Duration::from_secs(self.class.ping_frequency_secs);
+		match self.ping_sent {
+			Some(sent) => sent + frequency,
+			None => self.last_heard + frequency,
+		}
+	}
+
+	/// Write out as much queued output as the socket will take, and only
+	/// ask to hear about writability while some is left over. This is also
+	/// where a client that was KILLed or overflowed its SendQ is dropped.
+	pub fn flush(&mut self, registry: &Registry) {
+		if let Some(reason) = self.sendq.take_kill() {
+			self.disconnect(reason);
+		}
+		if self.sendq.is_exceeded() && !self.closing {
+			// the QUIT this sends to peers is flushed in the same pass of the
+			// reactor, which keeps draining pending writes until none are left
+			self.sendq.clear();
+			self.disconnect("Max SendQ exceeded".to_string());
+		}
+		if let Err(e) = self.sendq.flush_to(&mut self.stream).and_then(|_| self.stream.write_pending()) {
+			if !self.closing {
+				error!("Stream Write Error: {}", e);
+				self.sendq.clear();
+				self.disconnect(format!("Write error: {}", e));
+			}
+			return;
+		}
+		let awaiting_writable = !self.sendq.is_empty() || self.stream.wants_write();
+		if awaiting_writable != self.awaiting_writable {
+			let interest = if awaiting_writable {
+				Interest::READABLE | Interest::WRITABLE
 			} else {
-				if buffer.is_empty() { break; }
+				Interest::READABLE
+			};
+			if let Err(e) = registry.reregister(&mut self.stream, self.sendq.token(), interest) {
+				error!("Reregister Error: {}", e);
+			}
+			self.awaiting_writable = awaiting_writable;
+		}
+	}
 
-				match parse_message(buffer) {
-					Ok(Command::Nick(nick)) => { self.handle_nick(nick); },
-					Ok(Command::User(user)) => { self.handle_user(user); },
-					Ok(Command::Quit(quit_message)) => {
-						self.handle_quit(quit_message);
-						break;
-					},
-					Ok(Command::Privmsg(target, text)) => { self.handle_privmsg(target, text); },
-					Ok(Command::Notice(target, text)) => { self.handle_notice(target, text); },
-					Ok(Command::Ping) => { self.handle_ping(); },
-					Ok(Command::Pong) => {},
-					Ok(Command::Motd) => { self.handle_motd(); },
-					Ok(Command::Lusers) => { self.handle_lusers(); },
-					Ok(Command::Whois(target)) => { self.handle_whois(target); },
-					Ok(Command::Unknown(cmd)) => {self.send_err_unknowncommand(cmd); },
-					Err(e) => { error!("Message Parsing Error: {}", e); },
+	/// Act on the result of a password check once it is back from the
+	/// worker thread, then on the lines that waited for it.
+	pub fn finish_password_check(&mut self) {
+		let matched = match self.verifying {
+			Some((_, ref outcome)) => match outcome.try_recv() {
+				Ok(matched) => matched,
+				Err(TryRecvError::Empty) => { return; },
+				Err(TryRecvError::Disconnected) => false,
+			},
+			None => { return; },
+		};
+		match self.verifying.take() {
+			Some((Verifying::Registration, _)) => { self.finish_registration(matched); },
+			Some((Verifying::Oper(name), _)) => { self.finish_oper(name, matched); },
+			None => {},
+		}
+		self.process_lines();
+	}
+
+	/// Take up the limits our class has in the configuration as it stands,
+	/// after REHASH may have changed them.
+	pub fn reload_class(&mut self) {
+		self.class = self.config().class(&self.class.name);
+		self.flood.set_burst(Duration::from_millis(self.class.flood_burst_ms));
+		self.sendq.set_max_bytes(self.class.max_sendq);
+	}
+
+	pub fn is_closing(&self) -> bool {
+		self.closing
+	}
+
+	fn handle_line(&mut self, line: String) {
+		let command = parse_message(line);
+		if self.registration != Registration::Registered {
+			if let Ok(ref command) = command {
+				if !command.allowed_unregistered() {
+					self.send_err_notregistered();
+					return;
 				}
 			}
 		}
+
+		match command {
+			Ok(Command::Pass(password)) => { self.handle_pass(password); },
+			Ok(Command::Cap(subcommand, caps)) => { self.handle_cap(subcommand, caps); },
+			Ok(Command::Nick(nick)) => { self.handle_nick(nick); },
+			Ok(Command::User(user)) => { self.handle_user(user); },
+			Ok(Command::Quit(quit_message)) => { self.handle_quit(quit_message); },
+			Ok(Command::Privmsg(target, text)) => { self.handle_privmsg(target, text); },
+			Ok(Command::Notice(target, text)) => { self.handle_notice(target, text); },
+			Ok(Command::Join(channels, keys)) => { self.handle_join(channels, keys); },
+			Ok(Command::Part(channels, reason)) => { self.handle_part(channels, reason); },
+			Ok(Command::Topic(channel, topic)) => { self.handle_topic(channel, topic); },
+			Ok(Command::Names(channels)) => { self.handle_names(channels); },
+			Ok(Command::List(conditions)) => { self.handle_list(conditions); },
+			Ok(Command::Mode(target, modestring, params)) => {
+				self.handle_mode(target, modestring, params);
+			},
+			Ok(Command::Oper(name, password)) => { self.handle_oper(name, password); },
+			Ok(Command::Kill(target, comment)) => { self.handle_kill(target, comment); },
+			Ok(Command::Rehash) => { self.handle_rehash(); },
+			Ok(Command::Kick(channels, targets, comment)) => {
+				self.handle_kick(channels, targets, comment);
+			},
+			Ok(Command::Invite(target, channel)) => { self.handle_invite(target, channel); },
+			Ok(Command::Away(text)) => { self.handle_away(text); },
+			Ok(Command::Whowas(targets, count)) => { self.handle_whowas(targets, count); },
+			Ok(Command::Who(mask, options)) => { self.handle_who(mask, options); },
+			Ok(Command::Stats(query)) => { self.handle_stats(query); },
+			Ok(Command::Ping(token)) => { self.handle_ping(token); },
+			// any input at all answers a PING, see handle_readable
+			Ok(Command::Pong) => {},
+			Ok(Command::Motd) => { self.handle_motd(); },
+			Ok(Command::Lusers) => { self.handle_lusers(); },
+			Ok(Command::Admin(server)) => { self.handle_admin(server); },
+			Ok(Command::Whois(server, targets)) => { self.handle_whois(server, targets); },
+			Ok(Command::NeedMoreParams(cmd)) => { self.send_err_needmoreparams(&cmd); },
+			Ok(Command::Unknown(cmd)) => {self.send_err_unknowncommand(cmd); },
+			// anyone can send these, so they must not flood the log
+			Err(e) => { debug!("Message Parsing Error: {}", e); },
+		}
 	}
 
-	fn handle_nick(&mut self, nick: String) {
-		trace!("got NICK message\nnick: {}", nick);
+	fn handle_pass(&mut self, password: String) {
+		trace!("got PASS message");
+		if self.registration == Registration::Registered {
+			self.send_err_alreadyregistred();
+		} else {
+			self.password = Some(password);
+		}
+	}
 
-		self.my_nickname = Some(nick.clone());
+	/// We offer no capabilities yet, but take part in negotiation so that
+	/// clients which start with CAP LS can register.
+	fn handle_cap(&mut self, subcommand: String, caps: Vec<String>) {
+		trace!("got CAP message\nsubcommand: {}\ncaps: {:?}", subcommand, caps);
+		match subcommand.as_str() {
+			"LS" | "LIST" => {
+				if self.registration == Registration::Unregistered {
+					self.registration = Registration::Negotiating;
+				}
+				self.send_cap(&subcommand, "");
+			},
+			"REQ" => {
+				if self.registration == Registration::Unregistered {
+					self.registration = Registration::Negotiating;
+				}
+				self.send_cap("NAK", &caps.join(" "));
+			},
+			"END" => {
+				if self.registration == Registration::Negotiating {
+					self.registration = Registration::Unregistered;
+					self.try_register();
+				}
+			},
+			_ => { self.send_err_invalidcapcmd(&subcommand); },
+		}
+	}
 
-		let does_contain : bool;
+	/// Complete registration once we have both a nickname and a user, the
+	/// client is done negotiating capabilities and any server password
+	/// matches. A wrong password or a K-line drops the connection. Checking
+	/// a hashed password may finish later, in finish_password_check.
+	fn try_register(&mut self) {
+		if self.registration != Registration::Unregistered || self.my_nickname.is_none() {
+			return;
+		}
+		let has_user : bool;
 		{
-			let nn = self.nicknames.lock().unwrap();
-			does_contain = (*nn).contains_key(&nick);
+			let uu = self.users.lock().unwrap();
+			has_user = (*uu).contains_key(&self.peer_addr);
+		}
+		if !has_user {
+			return;
 		}
 
-		if does_contain { 
-	    	self.send_err_nicknameinuse(nick);
-	    } else {
+		let config = self.config();
+		let check = match (&config.password, &self.password) {
+			(Some(expected), Some(password)) => self.password_checker.check(password, expected,
+				self.sendq.token(), self.peer_addr.ip()),
+			(Some(_), None) => PasswordCheck::Done(false),
+			(None, _) => PasswordCheck::Done(true),
+		};
+		match check {
+			PasswordCheck::Done(password_ok) => { self.finish_registration(password_ok); },
+			PasswordCheck::Pending(outcome) => { self.verifying = Some((Verifying::Registration, outcome)); },
+		}
+	}
 
-			{
-				let mut nn = self.nicknames.lock().unwrap();
-				(*nn).insert(nick, self.peer_addr);
-			}
+	fn finish_registration(&mut self, password_ok: bool) {
+		if !password_ok {
+			self.send_err_passwdmismatch();
+			self.disconnect("Bad password".to_string());
+			return;
+		}
+		let (user, host) = (self.get_user(), self.get_host());
+		let kline = self.config().klines.iter().find(|kline| kline.matches(&user, &host)).cloned();
+		if let Some(kline) = kline {
+			self.send_err_yourebannedcreep(&kline.reason);
+			self.disconnect(format!("K-Lined: {}", kline.reason));
+			return;
+		}
+		self.registration = Registration::Registered;
+		self.send_welcome();
+	}
 
-			let has_user : bool;
-			{
-				let uu = self.users.lock().unwrap();
-				has_user = (*uu).contains_key(&self.peer_addr);
+	fn handle_nick(&mut self, nick: String) {
+		trace!("got NICK message\nnick: {}", nick);
+
+		if nick.is_empty() {
+			self.send_err_nonicknamegiven();
+			return;
+		}
+		let config = self.config();
+		if !is_valid_nickname(&nick, config.max_nick_len, config.casemapping) {
+			self.send_err_erroneusnickname(&nick);
+			return;
+		}
+		if self.my_nickname.as_ref() == Some(&nick) {
+			return;
+		}
+
+		// claim the new nickname and release the old one in one step, so
+		// no one else can slip in between
+		let claimed : bool;
+		{
+			let mut nn = self.nicknames.lock().unwrap();
+			claimed = match (*nn).get(self.fold(&nick).as_str()) {
+				Some(&addr) => addr == self.peer_addr,
+				None => true,
+			};
+			if claimed {
+				if let Some(ref old_nick) = self.my_nickname {
+					(*nn).remove(self.fold(old_nick).as_str());
+				}
+				(*nn).insert(self.config().casemapping.key(&nick), self.peer_addr);
 			}
+		}
+		if !claimed {
+			self.send_err_nicknameinuse(nick);
+			return;
+		}
+
+		if self.registration == Registration::Registered {
+			let old_nick = self.get_nickname();
+			self.change_nick(old_nick, nick);
+		} else {
+			self.my_nickname = Some(nick);
+			self.try_register();
+		}
+	}
 
-			if has_user {
-				self.send_welcome();
+	/// Complete a nick change by a registered user: tell ourselves and
+	/// everyone sharing a channel, and carry our channel memberships over.
+	fn change_nick(&mut self, old_nick: String, new_nick: String) {
+		let message = format!(":{} NICK {}", self.make_user_prefix(), new_nick);
+		self.record_whowas();
+		self.send_to_peers(message.clone());
+		{
+			let mut cc = self.channels.lock().unwrap();
+			for channel in (*cc).values_mut() {
+				channel.rename_member(&old_nick, &new_nick);
 			}
 		}
+		self.my_nickname = Some(new_nick);
+		self.write_reply(format!("{}\r\n", message));
 	}
 
-	fn handle_user(&mut self, user: User) {
+	fn handle_user(&mut self, mut user: User) {
 		trace!("got USER message\nuser: {}\nmode: {}\nrealname: {}",
-			user.user, user.mode, user.realname);
+			user.user, user.mode_string(), user.realname);
+		if self.registration == Registration::Registered {
+			self.send_err_alreadyregistred();
+			return;
+		}
+		user.secure = self.stream.is_secure();
 		{
 			let mut uu = self.users.lock().unwrap();
 			(*uu).insert(self.peer_addr, user);
 		}
-
-		let has_nick : bool;
-		{
-			let nn = self.nicknames.lock().unwrap();
-			has_nick = (*nn).values()
-		        .find(|&val| *val == self.peer_addr)
-		        .is_some();
-		}
-
-		if has_nick {
-			self.send_welcome();
-		}
+		self.try_register();
 	}
 
 	fn send_welcome(&mut self) {
@@ -138,115 +538,946 @@ impl Connection {
 		self.send_rpl_yourhost();
 		self.send_rpl_created();
 		self.send_rpl_myinfo();
+		self.send_rpl_isupport();
 		self.handle_lusers();
 		self.handle_motd();
 	}
 
 	fn handle_quit(&mut self, quit_message: String) {
 		trace!("got QUIT message\nquit_message: {}", quit_message);
+		// marked so a client cannot pass its message off as a server's
+		self.disconnect(format!("Quit: {}", quit_message));
+	}
+
+	/// Tear the connection down, whatever the reason it is going: its
+	/// channel peers are told it quit, it leaves every channel and all
+	/// shared state, and it is sent a parting ERROR. Only the first call
+	/// has any effect.
+	fn disconnect(&mut self, reason: String) {
+		if self.closing {
+			return;
+		}
+		self.closing = true;
+		info!("closing connection from {}: {}", self.peer_addr, reason);
+		if self.registration == Registration::Registered {
+			let quit = format!(":{} QUIT :{}", self.make_user_prefix(), reason);
+			self.send_to_peers(quit);
+			self.leave_all_channels();
+			self.record_whowas();
+		}
 		{ // remove self from shared data structures
 			let mut nn = self.nicknames.lock().unwrap();
 			let mut pb = self.phonebook.lock().unwrap();
 			let mut uu = self.users.lock().unwrap();
-			let target = self.get_nickname();
-			let target_addr = (*nn)[&target];
-			
-			if let Some(_) = (*nn).remove(&target) {
-				if let Some(_) = (*uu).remove(&target_addr) {
-					let mut n_users = self.num_known_users.lock().unwrap();
-					(*n_users) -= 1;
-				}
-			} else {
-				(*uu).remove(&target_addr);
+			if let Some(ref nick) = self.my_nickname {
+				(*nn).remove(self.fold(nick).as_str());
+			}
+			(*uu).remove(&self.peer_addr);
+			(*pb).remove(&self.peer_addr);
+			if self.registration == Registration::Registered {
+				let mut n_users = self.num_known_users.lock().unwrap();
+				(*n_users) -= 1;
 			}
-			(*pb).remove(&target_addr);
 		}
 
-		self.send_rpl_quit(quit_message);
+		self.send_rpl_quit(reason);
 	}
 
 	fn handle_privmsg(&mut self, target: String, text: String) {
 		trace!("got PRIVMSG message\ntarget: {}\ntext: {}", target, text);
-		let mut target_exists = false;
+		self.mark_active();
+		let full_message = format!(":{} PRIVMSG {} :{}",
+			self.make_user_prefix(),
+			target,
+			text);
+
+		let (status, name) = split_status_prefix(&target);
+		if is_channel_name(name) {
+			match self.can_speak_in(name) {
+				None => { self.send_err_nosuchchannel(name.to_string()); },
+				Some(false) => { self.send_err_cannotsendtochan(name.to_string()); },
+				Some(true) => { self.send_to_channel_status(name, status, full_message); },
+			}
+		} else if self.send_to_nick(&target, full_message) {
+			if let Some(away_message) = self.get_away(&target) {
+				self.send_rpl_away(&target, &away_message);
+			}
+		} else {
+			self.send_err_nosuchnick(target);
+		}
+	}
+
+	fn handle_notice(&mut self, target: String, text: String) {
+		trace!("got NOTICE message\ntarget: {}\ntext: {}", target, text);
+		self.mark_active();
+		let full_message = format!(":{} NOTICE {} :{}",
+			self.make_user_prefix(),
+			target,
+			text);
+
+		// NOTICE must never trigger an automatic reply
+		let (status, name) = split_status_prefix(&target);
+		if is_channel_name(name) {
+			if self.can_speak_in(name) == Some(true) {
+				self.send_to_channel_status(name, status, full_message);
+			}
+		} else {
+			self.send_to_nick(&target, full_message);
+		}
+	}
+
+	fn handle_join(&mut self, channels: Vec<String>, keys: Vec<String>) {
+		trace!("got JOIN message\nchannels: {:?}", channels);
+		let nick = self.get_nickname();
+		let hostmask = self.make_user_prefix();
+		let secure = self.stream.is_secure();
+		for (i, name) in channels.into_iter().enumerate() {
+			if name == "0" {
+				self.part_all_channels();
+				continue;
+			}
+			if !is_valid_channel_name(&name) {
+				self.send_err_nosuchchannel(name);
+				continue;
+			}
+
+			let newly_joined;
+			let channel_name;
+			{
+				let mut cc = self.channels.lock().unwrap();
+				let casemapping = self.config().casemapping;
+				let channel = (*cc).entry(casemapping.key(&name))
+					.or_insert_with(|| Channel::new(name.clone(), casemapping));
+				channel_name = channel.name.clone();
+				newly_joined = if channel.is_member(&nick) {
+					Ok(false)
+				} else {
+					channel.admit(&nick, &hostmask, keys.get(i), secure).map(|_| {
+						channel.add_member(&nick);
+						true
+					})
+				};
+			}
+			// the channel keeps the casing it was created with
+			let name = channel_name;
+
+			match newly_joined {
+				Err(JoinError::Banned) => { self.send_err_bannedfromchan(name); },
+				Err(JoinError::InviteOnly) => { self.send_err_inviteonlychan(name); },
+				Err(JoinError::BadKey) => { self.send_err_badchannelkey(name); },
+				Err(JoinError::Full) => { self.send_err_channelisfull(name); },
+				Err(JoinError::SecureOnly) => { self.send_err_secureonlychan(name); },
+				Ok(false) => {},
+				Ok(true) => {
+					let join_message = format!(":{} JOIN {}", self.make_user_prefix(), name);
+					self.send_to_channel(&name, join_message.clone());
+					self.write_reply(format!("{}\r\n", join_message));
+					self.send_topic(&name, false);
+					self.send_names(&name);
+					self.send_rpl_endofnames(&name);
+				},
+			}
+		}
+	}
+
+	fn handle_part(&mut self, channels: Vec<String>, reason: Option<String>) {
+		trace!("got PART message\nchannels: {:?}", channels);
+		let nick = self.get_nickname();
+		for name in channels {
+			let is_member;
+			{
+				let cc = self.channels.lock().unwrap();
+				is_member = (*cc).get(self.fold(&name).as_str()).map(|channel| channel.is_member(&nick));
+			}
+
+			match is_member {
+				None => { self.send_err_nosuchchannel(name); },
+				Some(false) => { self.send_err_notonchannel(name); },
+				Some(true) => {
+					let part_message = match reason {
+						Some(ref text) => format!(":{} PART {} :{}",
+							self.make_user_prefix(), name, text),
+						None => format!(":{} PART {}", self.make_user_prefix(), name),
+					};
+					self.send_to_channel(&name, part_message.clone());
+					self.write_reply(format!("{}\r\n", part_message));
+					self.leave_channel(&name);
+				},
+			}
+		}
+	}
+
+	fn handle_topic(&mut self, name: String, topic: Option<String>) {
+		trace!("got TOPIC message\nchannel: {}\ntopic: {:?}", name, topic);
+		let nick = self.get_nickname();
+		let text = match topic {
+			Some(text) => truncate_text(text, self.config().max_topic_len),
+			None => {
+				// secret and private channels do not exist to outsiders
+				let visible : bool;
+				{
+					let cc = self.channels.lock().unwrap();
+					visible = (*cc).get(self.fold(&name).as_str())
+						.is_some_and(|channel| channel.is_visible_to(&nick));
+				}
+				if visible {
+					self.send_topic(&name, true);
+				} else {
+					self.send_err_nosuchchannel(name);
+				}
+				return;
+			},
+		};
+
+		let (is_member, may_set) : (Option<bool>, bool);
 		{
-			let nn = self.nicknames.lock().unwrap();
-			if let Some(_) = (*nn).get(&target) {
-				target_exists = true;
+			let cc = self.channels.lock().unwrap();
+			let channel = (*cc).get(self.fold(&name).as_str())
+				.filter(|channel| channel.is_visible_to(&nick));
+			is_member = channel.map(|channel| channel.is_member(&nick));
+			may_set = channel.is_some_and(|channel|
+				!channel.topic_locked || channel.is_operator(&nick));
+		}
+
+		match is_member {
+			None => { self.send_err_nosuchchannel(name); },
+			Some(false) => { self.send_err_notonchannel(name); },
+			Some(true) if !may_set => { self.send_err_chanoprivsneeded(name); },
+			Some(true) => {
+				{
+					let mut cc = self.channels.lock().unwrap();
+					if let Some(channel) = (*cc).get_mut(self.fold(&name).as_str()) {
+						channel.set_topic(text.clone(), nick);
+					}
+				}
+				let topic_message = format!(":{} TOPIC {} :{}",
+					self.make_user_prefix(), name, text);
+				self.send_to_channel(&name, topic_message.clone());
+				self.write_reply(format!("{}\r\n", topic_message));
+			},
+		}
+	}
+
+	fn handle_mode(&mut self, target: String, modestring: Option<String>, params: Vec<String>) {
+		trace!("got MODE message\ntarget: {}\nmodestring: {:?}", target, modestring);
+		if is_channel_name(&target) {
+			self.handle_channel_mode(target, modestring, params);
+		} else {
+			self.handle_user_mode(target, modestring);
+		}
+	}
+
+	fn handle_user_mode(&mut self, target: String, modestring: Option<String>) {
+		if self.fold(&target) != self.fold(&self.get_nickname()) {
+			let target_exists : bool;
+			{
+				let nn = self.nicknames.lock().unwrap();
+				target_exists = (*nn).contains_key(self.fold(&target).as_str());
+			}
+			if target_exists {
+				self.send_err_usersdontmatch();
+			} else {
+				self.send_err_nosuchnick(target);
+			}
+			return;
+		}
+
+		let modestring = match modestring {
+			Some(modestring) => modestring,
+			None => {
+				let mode_string : String;
+				{
+					let uu = self.users.lock().unwrap();
+					mode_string = (*uu)[&self.peer_addr].mode_string();
+				}
+				self.send_rpl_umodeis(&mode_string);
+				return;
+			},
+		};
+
+		let mut applied = vec![];
+		let mut unknown_flag = false;
+		{
+			let mut uu = self.users.lock().unwrap();
+			if let Some(user) = (*uu).get_mut(&self.peer_addr) {
+				for change in parse_mode_changes(&modestring, &[], |_, _| false) {
+					match user.apply_mode(&change) {
+						Ok(true) => { applied.push(change); },
+						Ok(false) => {},
+						Err(_) => { unknown_flag = true; },
+					}
+				}
+			}
+		}
+
+		if unknown_flag {
+			self.send_err_umodeunknownflag();
+		}
+		if !applied.is_empty() {
+			let reply = format!(":{} MODE {} :{}\r\n",
+				self.get_nickname(),
+				target,
+				format_mode_changes(&applied));
+			self.write_reply(reply);
+		}
+	}
+
+	fn handle_channel_mode(&mut self, name: String, modestring: Option<String>, params: Vec<String>) {
+		let nick = self.get_nickname();
+		// secret and private channels do not exist to outsiders, whose
+		// queries or changes would otherwise give them away
+		let is_operator : Option<bool>;
+		{
+			let cc = self.channels.lock().unwrap();
+			is_operator = (*cc).get(self.fold(&name).as_str())
+				.filter(|channel| channel.is_visible_to(&nick))
+				.map(|channel| channel.is_operator(&nick));
+		}
+		let (is_operator, modestring) = match (is_operator, modestring) {
+			(None, _) => {
+				self.send_err_nosuchchannel(name);
+				return;
+			},
+			(Some(_), None) => {
+				self.send_channel_modes(&name);
+				return;
+			},
+			(Some(is_operator), Some(modestring)) => (is_operator, modestring),
+		};
+
+		// a list mode without a mask asks to see the list, which needs no privileges
+		let (list_requests, changes) : (Vec<_>, Vec<_>) =
+			parse_mode_changes(&modestring, &params, mode_takes_param).into_iter()
+				.partition(|change| LIST_MODES.contains(change.mode) && change.param.is_none());
+		for request in list_requests {
+			self.send_list_mode(&name, request.mode);
+		}
+		if changes.is_empty() {
+			return;
+		}
+		if !is_operator {
+			self.send_err_chanoprivsneeded(name);
+			return;
+		}
+
+		let mut applied = vec![];
+		let mut errors = vec![];
+		let set_by = self.make_user_prefix();
+		{
+			let mut cc = self.channels.lock().unwrap();
+			if let Some(channel) = (*cc).get_mut(self.fold(&name).as_str()) {
+				let mut num_params = 0;
+				for change in changes {
+					// changes with parameters past the limit are ignored,
+					// but those without one still apply
+					if change.param.is_some() {
+						num_params += 1;
+						if num_params > MAX_MODE_PARAMS {
+							continue;
+						}
+					}
+					match channel.apply_mode(&change, &set_by, self.config().max_list_entries) {
+						Ok(Some(applied_change)) => { applied.push(applied_change); },
+						Ok(None) => {},
+						Err(e) => { errors.push(e); },
+					}
+				}
+			}
+		}
+
+		for error in errors {
+			match error {
+				ModeError::UnknownMode(mode) => { self.send_err_unknownmode(mode, &name); },
+				ModeError::UserNotInChannel(target) => {
+					let target_exists : bool;
+					{
+						let nn = self.nicknames.lock().unwrap();
+						target_exists = (*nn).contains_key(self.fold(&target).as_str());
+					}
+					if target_exists {
+						self.send_err_usernotinchannel(target, &name);
+					} else {
+						self.send_err_nosuchnick(target);
+					}
+				},
+				ModeError::ListFull(mode) => { self.send_err_banlistfull(&name, mode); },
 			}
 		}
-		if target_exists {
+
+		if !applied.is_empty() {
+			let mode_message = format!(":{} MODE {} {}",
+				self.make_user_prefix(), name, format_mode_changes(&applied));
+			self.send_to_channel(&name, mode_message.clone());
+			self.write_reply(format!("{}\r\n", mode_message));
+		}
+	}
+
+	/// Send the entries of a +b, +e or +I list followed by its end reply.
+	fn send_list_mode(&mut self, name: &str, mode: char) {
+		let entries : Vec<(String, String, u64)>;
+		{
+			let cc = self.channels.lock().unwrap();
+			entries = match (*cc).get(self.fold(name).as_str()) {
+				Some(channel) => channel.list(mode).iter()
+					.map(|entry| (entry.mask.clone(), entry.set_by.clone(), entry.set_at))
+					.collect(),
+				None => vec![],
+			};
+		}
+		let (entry_code, end_code, end_text) = match mode {
+			'b' => (367, 368, "End of channel ban list"),
+			'e' => (348, 349, "End of channel exception list"),
+			_ => (346, 347, "End of channel invite list"),
+		};
+		for (mask, set_by, set_at) in entries {
+			let reply = format!("{}{} {} {} {}\r\n",
+				self.make_prefix(entry_code),
+				name,
+				mask,
+				set_by,
+				set_at);
+			self.write_reply(reply);
+		}
+		let reply = format!("{}{} :{}\r\n",
+			self.make_prefix(end_code),
+			name,
+			end_text);
+		self.write_reply(reply);
+	}
+
+	fn send_channel_modes(&mut self, name: &str) {
+		let nick = self.get_nickname();
+		let modes : Option<(String, u64)>;
+		{
+			let cc = self.channels.lock().unwrap();
+			modes = (*cc).get(self.fold(name).as_str()).map(|channel|
+				(channel.mode_string(channel.is_member(&nick)), channel.created_at));
+		}
+		if let Some((mode_string, created_at)) = modes {
+			self.send_rpl_channelmodeis(name, &mode_string);
+			self.send_rpl_creationtime(name, created_at);
+		}
+	}
+
+	fn handle_oper(&mut self, name: String, password: String) {
+		trace!("got OPER message\nname: {}", name);
+		let check = match self.config().operators.iter().find(|operator| operator.name == name) {
+			Some(operator) => operator.check(&password, &self.password_checker, self.sendq.token(),
+				self.peer_addr.ip()),
+			None => PasswordCheck::Done(false),
+		};
+		match check {
+			PasswordCheck::Done(authenticated) => { self.finish_oper(name, authenticated); },
+			PasswordCheck::Pending(outcome) => { self.verifying = Some((Verifying::Oper(name), outcome)); },
+		}
+	}
+
+	fn finish_oper(&mut self, name: String, authenticated: bool) {
+		if !authenticated {
+			warn!("failed OPER attempt as {} from {}", name, self.peer_addr);
+			self.send_err_passwdmismatch();
+			return;
+		}
+
+		let newly_opered : bool;
+		{
+			let mut uu = self.users.lock().unwrap();
+			let user = (*uu).get_mut(&self.peer_addr).unwrap();
+			newly_opered = !user.operator;
+			user.operator = true;
+		}
+		info!("{} is now an operator ({})", self.get_nickname(), name);
+		if newly_opered {
+			let reply = format!(":{} MODE {} :+o\r\n",
+				self.get_nickname(),
+				self.get_nickname());
+			self.write_reply(reply);
+		}
+		self.send_rpl_youreoper();
+	}
+
+	/// Disconnect another client. Its channel peers see it quit with the
+	/// operator's name and comment as the reason.
+	fn handle_kill(&mut self, target: String, comment: String) {
+		trace!("got KILL message\ntarget: {}\ncomment: {}", target, comment);
+		if !self.is_operator() {
+			self.send_err_noprivileges();
+			return;
+		}
+
+		let victim : Option<(String, SendQueue)>;
+		{
 			let nn = self.nicknames.lock().unwrap();
 			let pb = self.phonebook.lock().unwrap();
-			let target_addr = (*nn)[&target];
-			let target_tx = &(*pb)[&target_addr];
+			victim = (*nn).get_key_value(self.fold(&target).as_str())
+				.and_then(|(nick, addr)| (*pb).get(addr)
+					.map(|queue| (nick.to_string(), queue.clone())));
+		}
+		match victim {
+			Some((nick, queue)) => {
+				info!("{} killed {} ({})", self.get_nickname(), nick, comment);
+				queue.push(&format!(":{} KILL {} :{}\r\n", self.make_user_prefix(), nick, comment));
+				queue.kill(format!("Killed ({} ({}))", self.get_nickname(), comment));
+			},
+			None => { self.send_err_nosuchnick(target); },
+		}
+	}
+
+	/// Reload the configuration, operators, K-lines and TLS certificates,
+	/// keeping all of the old ones if anything new cannot be used. Reports
+	/// what went wrong, or what needs a restart, in server notices.
+	fn handle_rehash(&mut self) {
+		trace!("got REHASH message");
+		if !self.is_operator() {
+			self.send_err_noprivileges();
+			return;
+		}
 
-			let prefix = format!(":{}!{}@{} PRIVMSG {} :", 
-				self.get_nickname(), 
-				self.get_user(), 
-				self.local_addr, 
-				target);
-			let full_message = format!("{}{}", prefix, text);
+		let file = self.config().path.clone().unwrap_or_else(|| "*".to_string());
+		self.send_rpl_rehashing(&file);
+		match self.rehasher.rehash() {
+			Ok(unchanged) => {
+				info!("{} reloaded the configuration", self.get_nickname());
+				for setting in unchanged {
+					warn!("the {} cannot change until the server restarts", setting);
+					self.send_server_notice(&format!("The {} cannot change until the server restarts", setting));
+				}
+			},
+			Err(e) => {
+				warn!("could not reload the configuration: {}", e);
+				self.send_server_notice(&format!("Keeping the current configuration: {}", e));
+			},
+		}
+	}
 
-			(*target_tx).send(full_message).unwrap();
+	fn handle_kick(&mut self, channels: Vec<String>, targets: Vec<String>, comment: Option<String>) {
+		trace!("got KICK message\nchannels: {:?}\ntargets: {:?}", channels, targets);
+		// either one channel and many users, or pairs of channel and user
+		let kicks : Vec<(String, String)> = if channels.len() == 1 {
+			targets.into_iter().map(|target| (channels[0].clone(), target)).collect()
 		} else {
-			self.send_err_nosuchnick(target);
+			channels.into_iter().zip(targets).collect()
+		};
+
+		let nick = self.get_nickname();
+		let comment = truncate_text(comment.unwrap_or_else(|| nick.clone()), self.config().max_kick_len);
+		for (name, target) in kicks {
+			let (is_member, is_operator, target_is_member) : (Option<bool>, bool, bool);
+			{
+				let cc = self.channels.lock().unwrap();
+				let channel = (*cc).get(self.fold(&name).as_str());
+				is_member = channel.map(|channel| channel.is_member(&nick));
+				is_operator = channel.is_some_and(|channel| channel.is_operator(&nick));
+				target_is_member = channel.is_some_and(|channel| channel.is_member(&target));
+			}
+
+			match is_member {
+				None => { self.send_err_nosuchchannel(name); },
+				Some(false) => { self.send_err_notonchannel(name); },
+				Some(true) if !is_operator => { self.send_err_chanoprivsneeded(name); },
+				Some(true) if !target_is_member => { self.send_err_usernotinchannel(target, &name); },
+				Some(true) => {
+					let kick_message = format!(":{} KICK {} {} :{}",
+						self.make_user_prefix(), name, target, comment);
+					self.send_to_channel(&name, kick_message.clone());
+					self.write_reply(format!("{}\r\n", kick_message));
+					self.remove_from_channel(&name, &target);
+				},
+			}
 		}
 	}
 
-	fn handle_notice(&mut self, target: String, text: String) {
-		trace!("got NOTICE message\ntarget: {}\ntext: {}", target, text);
-		let mut target_exists = false;
+	fn handle_invite(&mut self, target: String, name: String) {
+		trace!("got INVITE message\ntarget: {}\nchannel: {}", target, name);
+		let target_exists : bool;
 		{
 			let nn = self.nicknames.lock().unwrap();
-			if let Some(_) = (*nn).get(&target) {
-				target_exists = true;
+			target_exists = (*nn).contains_key(self.fold(&target).as_str());
+		}
+		if !target_exists {
+			self.send_err_nosuchnick(target);
+			return;
+		}
+
+		// inviting to a channel that does not exist yet needs no privileges
+		let nick = self.get_nickname();
+		let (is_member, may_invite, target_is_member) : (Option<bool>, bool, bool);
+		{
+			let cc = self.channels.lock().unwrap();
+			let channel = (*cc).get(self.fold(&name).as_str());
+			is_member = channel.map(|channel| channel.is_member(&nick));
+			may_invite = channel.is_none_or(|channel|
+				!channel.invite_only || channel.is_operator(&nick));
+			target_is_member = channel.is_some_and(|channel| channel.is_member(&target));
+		}
+
+		match is_member {
+			Some(false) => { self.send_err_notonchannel(name); },
+			_ if !may_invite => { self.send_err_chanoprivsneeded(name); },
+			_ if target_is_member => { self.send_err_useronchannel(target, &name); },
+			_ => {
+				{
+					let mut cc = self.channels.lock().unwrap();
+					if let Some(channel) = (*cc).get_mut(self.fold(&name).as_str()) {
+						channel.invite(&target);
+					}
+				}
+				let invite_message = format!(":{} INVITE {} {}",
+					self.make_user_prefix(), target, name);
+				self.send_to_nick(&target, invite_message);
+				self.send_rpl_inviting(&target, &name);
+				if let Some(away_message) = self.get_away(&target) {
+					self.send_rpl_away(&target, &away_message);
+				}
+			},
+		}
+	}
+
+	fn handle_away(&mut self, text: Option<String>) {
+		trace!("got AWAY message\ntext: {:?}", text);
+		let text = text.map(|text| truncate_text(text, self.config().max_away_len));
+		let now_away = text.is_some();
+		{
+			let mut uu = self.users.lock().unwrap();
+			if let Some(user) = (*uu).get_mut(&self.peer_addr) {
+				user.away = text;
+			}
+		}
+		if now_away {
+			self.send_rpl_nowaway();
+		} else {
+			self.send_rpl_unaway();
+		}
+	}
+
+	fn handle_who(&mut self, mask: Option<String>, options: Option<String>) {
+		trace!("got WHO message\nmask: {:?}\noptions: {:?}", mask, options);
+		let mask = match mask {
+			Some(ref mask) if mask != "0" => mask.clone(),
+			_ => "*".to_string(),
+		};
+		// `o%tcnf,42` asks for operators only, with WHOX fields t,c,n,f and token 42
+		let options = options.unwrap_or_default();
+		let (flags, whox) = match options.find('%') {
+			Some(ix) => (&options[..ix], Some(&options[ix+1..])),
+			None => (&options[..], None),
+		};
+		let operators_only = flags.contains('o');
+
+		let mut entries = if is_channel_name(&mask) {
+			self.who_channel(&mask)
+		} else {
+			self.who_mask(&mask)
+		};
+		if operators_only {
+			entries.retain(|entry| entry.user.operator);
+		}
+
+		for entry in entries {
+			match whox {
+				Some(whox) => { self.send_rpl_whospcrpl(&entry, whox); },
+				None => { self.send_rpl_whoreply(&entry); },
+			}
+		}
+		self.send_rpl_endofwho(&mask);
+	}
+
+	/// The members of a channel we can see: everyone if we are in it,
+	/// otherwise only visible users of a public channel.
+	fn who_channel(&self, name: &str) -> Vec<WhoEntry> {
+		let nick = self.get_nickname();
+		let members : Vec<(String, &'static str)>;
+		let is_member : bool;
+		{
+			let cc = self.channels.lock().unwrap();
+			match (*cc).get(self.fold(name).as_str()) {
+				Some(channel) if channel.is_visible_to(&nick) => {
+					members = channel.members.iter()
+						.map(|(member, status)| (member.to_string(), status.prefix()))
+						.collect();
+					is_member = channel.is_member(&nick);
+				},
+				_ => { return vec![]; },
+			}
+		}
+
+		let nn = self.nicknames.lock().unwrap();
+		let uu = self.users.lock().unwrap();
+		let mut entries : Vec<WhoEntry> = members.into_iter()
+			.filter_map(|(member, prefix)| {
+				let addr = *(*nn).get(self.fold(&member).as_str())?;
+				let user = (*uu).get(&addr)?.clone();
+				Some(WhoEntry {channel: name.to_string(), nick: member, user, addr, prefix})
+			})
+			.filter(|entry| is_member || !entry.user.invisible)
+			.collect();
+		entries.sort_by(|a, b| a.nick.cmp(&b.nick));
+		entries
+	}
+
+	/// Users whose nick, username, host, server or real name match the
+	/// mask under the casemapping. Invisible users only show up if we share
+	/// a channel with them.
+	fn who_mask(&self, mask: &str) -> Vec<WhoEntry> {
+		let nick = self.get_nickname();
+		let server = self.config().server_name.clone();
+		let mask = self.fold(mask);
+		let neighbours : HashSet<CaseKey>;
+		{
+			let cc = self.channels.lock().unwrap();
+			neighbours = (*cc).values()
+				.filter(|channel| channel.is_member(&nick))
+				.flat_map(|channel| channel.members.keys().cloned())
+				.collect();
+		}
+
+		let nn = self.nicknames.lock().unwrap();
+		let uu = self.users.lock().unwrap();
+		let mut entries : Vec<WhoEntry> = (*nn).iter()
+			.filter_map(|(member, addr)| {
+				let user = (*uu).get(addr)?.clone();
+				Some(WhoEntry {channel: "*".to_string(), nick: member.to_string(), user, addr: *addr, prefix: ""})
+			})
+			.filter(|entry| !entry.user.invisible || entry.nick == nick
+				|| neighbours.contains(self.fold(&entry.nick).as_str()))
+			.filter(|entry| [&entry.nick, &entry.user.user, &format_host(&entry.addr), &server, &entry.user.realname]
+				.iter()
+				.any(|field| wildcard_match(&mask, &self.fold(field))))
+			.collect();
+		entries.sort_by(|a, b| a.nick.cmp(&b.nick));
+		entries
+	}
+
+	fn handle_names(&mut self, channels: Vec<String>) {
+		trace!("got NAMES message\nchannels: {:?}", channels);
+		if !channels.is_empty() {
+			for name in channels {
+				self.send_names(&name);
+				self.send_rpl_endofnames(&name);
 			}
+			return;
 		}
-		if target_exists {
+
+		let nick = self.get_nickname();
+		let visible_channels : Vec<String>;
+		let mut unlisted_users : Vec<String>;
+		{
+			let cc = self.channels.lock().unwrap();
 			let nn = self.nicknames.lock().unwrap();
-			let pb = self.phonebook.lock().unwrap();
-			let target_addr = (*nn)[&target];
-			let target_tx = &(*pb)[&target_addr];
+			visible_channels = (*cc).values()
+				.filter(|channel| channel.is_visible_to(&nick))
+				.map(|channel| channel.name.clone())
+				.collect();
+			unlisted_users = (*nn).keys()
+				.filter(|&user| !(*cc).values().any(|channel|
+					channel.is_visible_to(&nick) && channel.is_member(user.as_str())))
+				.map(|user| user.to_string())
+				.collect();
+		}
+		// nobody shares a channel with these users, so hide the invisible ones
+		unlisted_users.retain(|user| *user == nick || !self.is_invisible(user));
+		unlisted_users.sort();
+
+		for name in visible_channels {
+			self.send_names(&name);
+		}
+		self.send_rpl_namreplies('*', "*", unlisted_users);
+		self.send_rpl_endofnames("*");
+	}
+
+	fn handle_list(&mut self, conditions: Vec<String>) {
+		trace!("got LIST message\nconditions: {:?}", conditions);
+		let nick = self.get_nickname();
+		let filter = ListFilter::new(&conditions);
+		let mut listing : Vec<(String, usize, String)>;
+		{
+			let cc = self.channels.lock().unwrap();
+			listing = (*cc).values()
+				.filter(|channel| channel.is_visible_to(&nick) && filter.matches(channel))
+				.map(|channel| (
+					channel.name.clone(),
+					channel.members.len(),
+					channel.topic.as_ref().map_or(String::new(), |topic| topic.text.clone())))
+				.collect();
+		}
+		listing.sort();
+
+		for (name, num_users, topic) in listing {
+			self.send_rpl_list(&name, num_users, &topic);
+		}
+		self.send_rpl_listend();
+	}
+
+	fn part_all_channels(&mut self) {
+		let nick = self.get_nickname();
+		let joined_channels : Vec<String>;
+		{
+			let cc = self.channels.lock().unwrap();
+			joined_channels = (*cc).values()
+				.filter(|channel| channel.is_member(&nick))
+				.map(|channel| channel.name.clone())
+				.collect();
+		}
+		self.handle_part(joined_channels, None);
+	}
 
-			let prefix = format!(":{}!{}@{} NOTICE {} :", 
-				self.get_nickname(), 
-				self.get_user(), 
-				self.local_addr, 
-				target);
-			let full_message = format!("{}{}", prefix, text);
+	fn leave_channel(&mut self, name: &str) {
+		let nick = self.get_nickname();
+		self.remove_from_channel(name, &nick);
+	}
+
+	/// Remove a member, dropping the channel once nobody is left in it.
+	fn remove_from_channel(&self, name: &str, nick: &str) {
+		let mut cc = self.channels.lock().unwrap();
+		let now_empty = match (*cc).get_mut(self.fold(name).as_str()) {
+			Some(channel) => {
+				channel.remove_member(nick);
+				channel.is_empty()
+			},
+			None => false,
+		};
+		if now_empty {
+			(*cc).remove(self.fold(name).as_str());
+		}
+	}
+
+	fn leave_all_channels(&mut self) {
+		if let Some(nick) = self.my_nickname.clone() {
+			let mut cc = self.channels.lock().unwrap();
+			for channel in (*cc).values_mut() {
+				channel.remove_member(&nick);
+			}
+			(*cc).retain(|_, channel| !channel.is_empty());
+		}
+	}
+
+	/// Send RPL_TOPIC and RPL_TOPICWHOTIME, or RPL_NOTOPIC if no topic is
+	/// set and the client explicitly asked for it.
+	fn send_topic(&mut self, name: &str, reply_if_unset: bool) {
+		let topic : Option<(String, String, u64)>;
+		{
+			let cc = self.channels.lock().unwrap();
+			topic = (*cc).get(self.fold(name).as_str())
+				.and_then(|channel| channel.topic.as_ref())
+				.map(|topic| (topic.text.clone(), topic.set_by.clone(), topic.set_at));
+		}
+		match topic {
+			Some((text, set_by, set_at)) => {
+				self.send_rpl_topic(name, &text);
+				self.send_rpl_topicwhotime(name, &set_by, set_at);
+			},
+			None if reply_if_unset => { self.send_rpl_notopic(name); },
+			None => {},
+		}
+	}
+
+	/// Send RPL_NAMREPLY for a channel, if it exists and is visible to us.
+	fn send_names(&mut self, name: &str) {
+		let nick = self.get_nickname();
+		let mut members : Vec<(String, &'static str)>;
+		let symbol : char;
+		let is_member : bool;
+		{
+			let cc = self.channels.lock().unwrap();
+			match (*cc).get(self.fold(name).as_str()) {
+				Some(channel) if channel.is_visible_to(&nick) => {
+					members = channel.members.iter()
+						.map(|(member, status)| (member.to_string(), status.prefix()))
+						.collect();
+					symbol = channel.names_symbol();
+					is_member = channel.is_member(&nick);
+				},
+				_ => { return; },
+			}
+		}
+		if !is_member {
+			members.retain(|(member, _)| !self.is_invisible(member));
+		}
+		members.sort();
+		let names = members.into_iter()
+			.map(|(member, prefix)| format!("{}{}", prefix, member))
+			.collect();
+		self.send_rpl_namreplies(symbol, name, names);
+	}
 
-			(*target_tx).send(full_message).unwrap();
+	/// Only `STATS l` is answered. Operators see every connection, other
+	/// users just their own.
+	fn handle_stats(&mut self, query: Option<String>) {
+		trace!("got STATS message\nquery: {:?}", query);
+		let query = query.and_then(|query| query.chars().next()).unwrap_or('*');
+		if query == 'l' || query == 'L' {
+			let is_operator = self.is_operator();
+			let links : Vec<(String, usize, LinkStats)>;
+			{
+				let nn = self.nicknames.lock().unwrap();
+				let pb = self.phonebook.lock().unwrap();
+				let uu = self.users.lock().unwrap();
+				links = (*pb).iter()
+					.filter(|&(addr, _)| is_operator || *addr == self.peer_addr)
+					.map(|(addr, sendq)| {
+						let nick = (*nn).iter()
+							.find(|&(_, nick_addr)| nick_addr == addr)
+							.map_or("*".to_string(), |(nick, _)| nick.to_string());
+						let user = (*uu).get(addr).map_or("unknown".to_string(), |user| user.user.clone());
+						(format!("{}[{}@{}]", nick, user, format_host(addr)), sendq.len(), sendq.stats())
+					})
+					.collect();
+			}
+			for (name, queued, stats) in links {
+				self.send_rpl_statslinkinfo(&name, queued, &stats);
+			}
 		}
+		self.send_rpl_endofstats(query);
 	}
 
-	fn handle_ping(&mut self) {
-		let reply = format!("PONG {}\r\n", self.local_addr);
+	fn handle_ping(&mut self, token: String) {
+		trace!("got PING message\ntoken: {}", token);
+		if token.is_empty() {
+			self.send_err_noorigin();
+			return;
+		}
+		let reply = format!(":{} PONG {} :{}\r\n", self.config().server_name, self.config().server_name, token);
 		self.write_reply(reply);
 	}
 
 	fn handle_motd(&mut self) {
-		let f_result = File::open("motd.txt");
-		if let Err(_) = f_result {
-			self.send_err_nomotd();
-		} else {
+		let f_result = File::open(&self.config().motd_path);
+		if let Ok(f) = f_result {
 			self.send_rpl_motd_start();
-			let mut buffer = [0; 80];
-			let mut f = f_result.unwrap();
-			loop {
-				let fread = f.read(&mut buffer);
-				match fread {
-					Ok(n) if n > 0 => {
-						let reply = format!(":{} 372 {} :- {}\r\n", 
-							self.local_addr, 
+			for line in BufReader::new(f).lines() {
+				match line {
+					Ok(line) => {
+						let reply = format!(":{} 372 {} :- {}\r\n",
+							self.config().server_name,
 							self.get_nickname(),
-							str::from_utf8(&buffer[..n]).unwrap());
+							line);
 						self.write_reply(reply);
 					},
-					_ => { break; }
+					Err(e) => {
+						error!("MOTD Read Error: {}", e);
+						break;
+					},
 				}
 			}
 			self.send_rpl_motd_end();
+		} else {
+			self.send_err_nomotd();
+		}
+	}
+
+	fn handle_admin(&mut self, server: Option<String>) {
+		trace!("got ADMIN message\nserver: {:?}", server);
+		if let Some(server) = server {
+			if !wildcard_match(&server, &self.config().server_name) {
+				self.send_err_nosuchserver(&server);
+				return;
+			}
 		}
+		let config = self.config();
+		self.send_rpl_adminme();
+		self.send_rpl_admin(257, &config.admin.location);
+		self.send_rpl_admin(258, &config.admin.details);
+		self.send_rpl_admin(259, &config.admin.email);
 	}
 
 	fn handle_lusers(&mut self) {
@@ -257,77 +1488,216 @@ impl Connection {
 		self.send_rpl_luserme();
 	}
 
-	fn handle_whois(&mut self, target: String) {
-		let mut target_exists = false;
-		{
-			let nn = self.nicknames.lock().unwrap();
-			if let Some(_) = (*nn).get(&target) {
-				target_exists = true;
+	fn handle_whois(&mut self, server: Option<String>, targets: Vec<String>) {
+		trace!("got WHOIS message\nserver: {:?}\ntargets: {:?}", server, targets);
+		// `WHOIS server nick` is answered locally if it names us, or
+		// repeats the nickname to ask for idle time
+		if let Some(server) = server {
+			let our_name = self.config().server_name.clone();
+			if !wildcard_match(&server, &our_name) && !targets.contains(&server) {
+				self.send_err_nosuchserver(&server);
+				return;
 			}
 		}
-		if target_exists {
-			let target_user;
-			let target_addr;
+
+		for target in targets {
+			let target_info : Option<(String, User, SocketAddr)>;
 			{
 				let nn = self.nicknames.lock().unwrap();
 				let uu = self.users.lock().unwrap();
-				target_addr = (*nn)[&target].clone();
-				target_user = (*uu)[&target_addr].clone();
+				target_info = (*nn).get_key_value(self.fold(&target).as_str())
+					.and_then(|(nick, addr)| (*uu).get(addr)
+						.map(|user| (nick.to_string(), user.clone(), *addr)));
 			}
-			self.send_rpl_whoisuser(target.clone(), target_user, target_addr);
-			self.send_rpl_whoisserver(target.clone(), target_addr);
-			self.send_rpl_endofwhois(target.clone());
-		} else {
-			self.send_err_nosuchnick(target);
+
+			// replies carry the nickname as its owner cased it
+			if let Some((nick, target_user, target_addr)) = target_info {
+				self.send_rpl_whoisuser(&nick, &target_user, target_addr);
+				self.send_whois_channels(&nick);
+				self.send_rpl_whoisserver(&nick);
+				if let Some(ref away_message) = target_user.away {
+					self.send_rpl_away(&nick, away_message);
+				}
+				if target_user.operator {
+					self.send_rpl_whoisoperator(&nick);
+				}
+				if target_user.secure {
+					self.send_rpl_whoissecure(&nick);
+				}
+				self.send_rpl_whoisidle(&nick, &target_user);
+			} else {
+				self.send_err_nosuchnick(target.clone());
+			}
+			self.send_rpl_endofwhois(target);
+		}
+	}
+
+	fn handle_whowas(&mut self, targets: Vec<String>, count: usize) {
+		trace!("got WHOWAS message\ntargets: {:?}\ncount: {}", targets, count);
+		for target in targets {
+			let entries : Vec<WhowasEntry>;
+			{
+				let ww = self.whowas.lock().unwrap();
+				entries = (*ww).lookup(&target, count);
+			}
+			if entries.is_empty() {
+				self.send_err_wasnosuchnick(&target);
+			}
+			for entry in entries {
+				self.send_rpl_whowasuser(&entry);
+				self.send_rpl_whowasserver(&entry);
+			}
+			self.send_rpl_endofwhowas(&target);
+		}
+	}
+
+	/// Remember our current identity for WHOWAS before it goes away.
+	fn record_whowas(&mut self) {
+		let user : Option<User>;
+		{
+			let uu = self.users.lock().unwrap();
+			user = (*uu).get(&self.peer_addr).cloned();
+		}
+		if let (Some(nick), Some(user)) = (self.my_nickname.clone(), user) {
+			let entry = WhowasEntry::new(nick, user.user, self.get_host(),
+				user.realname, self.config().server_name.clone());
+			let mut ww = self.whowas.lock().unwrap();
+			(*ww).record(entry);
+		}
+	}
+
+	/// RPL_WHOISCHANNELS, leaving out secret and private channels we are
+	/// not in ourselves.
+	fn send_whois_channels(&mut self, target: &str) {
+		let nick = self.get_nickname();
+		let mut channels : Vec<String>;
+		{
+			let cc = self.channels.lock().unwrap();
+			channels = (*cc).values()
+				.filter(|channel| channel.is_visible_to(&nick))
+				.filter_map(|channel| channel.members.get(self.fold(target).as_str())
+					.map(|status| format!("{}{}", status.prefix(), channel.name)))
+				.collect();
+		}
+		channels.sort();
+		for line in wrap_words(channels) {
+			self.send_rpl_whoischannels(target, &line);
+		}
+	}
+
+	fn send_rpl_motd_start(&mut self) {
+		let reply = format!("{}:- {} Message of the day - \r\n",
+			self.make_prefix(375),
+			self.config().server_name);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_motd_end(&mut self) {
+		let reply = format!("{}:End of MOTD command\r\n",
+			self.make_prefix(376));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_welcome(&mut self) {
+		let reply = format!("{}:Welcome to the Internet Relay Network {}\r\n",
+			self.make_prefix(1),
+			self.make_user_prefix());
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_quit(&mut self, quit_message: String) {
+		let reply = format!("ERROR :Closing Link: {} ({})\r\n",
+				self.peer_addr,
+				quit_message);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_yourhost(&mut self) {
+		let reply = format!("{}:Your host is {}, running version {}\r\n",
+			self.make_prefix(2),
+			self.config().server_name,
+			VERSION);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_created(&mut self) {
+		let reply = format!("{}:This server was created {}\r\n",
+			self.make_prefix(3),
+			format_timestamp(self.started_at));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_myinfo(&mut self) {
+		let reply = format!("{}{} {} {} {}\r\n",
+			self.make_prefix(4),
+			self.config().server_name,
+			VERSION,
+			USER_MODES,
+			CHANNEL_MODES);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_isupport(&mut self) {
+		for line in ISupport::new(&self.config()).lines() {
+			let reply = format!("{}{} :are supported by this server\r\n",
+				self.make_prefix(5),
+				line);
+			self.write_reply(reply);
 		}
 	}
 
-	fn send_rpl_motd_start(&mut self) {
-		let reply = format!("{}:- {} Message of the day - \r\n",
-			self.make_prefix(375),
-			self.local_addr);
+	fn send_rpl_statslinkinfo(&mut self, name: &str, queued: usize, stats: &LinkStats) {
+		let reply = format!("{}{} {} {} {} {} {} {}\r\n",
+			self.make_prefix(211),
+			name,
+			queued,
+			stats.sent_messages,
+			stats.sent_bytes / 1024,
+			stats.received_messages,
+			stats.received_bytes / 1024,
+			unix_timestamp().saturating_sub(stats.opened_at));
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_motd_end(&mut self) {
-		let reply = format!("{}:End of MOTD command\r\n",
-			self.make_prefix(376));
+	fn send_rpl_endofstats(&mut self, query: char) {
+		let reply = format!("{}{} :End of STATS report\r\n",
+			self.make_prefix(219),
+			query);
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_welcome(&mut self) {
-		let reply = format!("{}:Welcome to the Internet Relay Network {}!{}@{}\r\n",
-			self.make_prefix(001),
-			self.get_nickname(),
-			self.get_user(),
-			self.peer_addr);
+	fn send_rpl_umodeis(&mut self, mode_string: &str) {
+		let reply = format!("{}{}\r\n",
+			self.make_prefix(221),
+			mode_string);
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_quit(&mut self, quit_message: String) {
-		let reply = format!("ERROR :Closing Link: {} ({})\r\n",
-				self.peer_addr,
-				quit_message);
+	fn send_rpl_youreoper(&mut self) {
+		let reply = format!("{}:You are now an IRC operator\r\n",
+			self.make_prefix(381));
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_yourhost(&mut self) {
-		let reply = format!("{}:Your host is {}, running version 0.1\r\n",
-			self.make_prefix(002),
-			self.local_addr);
+	fn send_rpl_adminme(&mut self) {
+		let reply = format!("{}{} :Administrative info\r\n",
+			self.make_prefix(256),
+			self.config().server_name);
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_created(&mut self) {
-		let reply = format!("{}:This server was created SOMEDATE\r\n",
-			self.make_prefix(003));
+	/// RPL_ADMINLOC1, RPL_ADMINLOC2 or RPL_ADMINEMAIL.
+	fn send_rpl_admin(&mut self, repl_code: u16, text: &str) {
+		let reply = format!("{}:{}\r\n",
+			self.make_prefix(repl_code),
+			text);
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_myinfo(&mut self) {
-		let reply = format!("{}{} 0.1 ao mtov\r\n",
-			self.make_prefix(004),
-			self.local_addr);
+	fn send_rpl_rehashing(&mut self, file: &str) {
+		let reply = format!("{}{} :Rehashing\r\n",
+			self.make_prefix(382),
+			file);
 		self.write_reply(reply);
 	}
 
@@ -339,8 +1709,9 @@ impl Connection {
 	}
 
 	fn send_rpl_luserop(&mut self) {
-		let reply = format!("{}0 :operator(s) online\r\n",
-			self.make_prefix(252));
+		let reply = format!("{}{} :operator(s) online\r\n",
+			self.make_prefix(252),
+			self.get_num_operators());
 		self.write_reply(reply);
 	}
 
@@ -352,8 +1723,9 @@ impl Connection {
 	}
 
 	fn send_rpl_luserchannels(&mut self) {
-		let reply = format!("{}0 :channels formed\r\n",
-			self.make_prefix(254));
+		let reply = format!("{}{} :channels formed\r\n",
+			self.make_prefix(254),
+			self.get_num_channels());
 		self.write_reply(reply);
 	}
 
@@ -364,21 +1736,141 @@ impl Connection {
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_whoisuser(&mut self, nick: String, user: User, host: SocketAddr) {
+	fn send_rpl_away(&mut self, nick: &str, away_message: &str) {
+		let reply = format!("{}{} :{}\r\n",
+			self.make_prefix(301),
+			nick,
+			away_message);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_unaway(&mut self) {
+		let reply = format!("{}:You are no longer marked as being away\r\n",
+			self.make_prefix(305));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_nowaway(&mut self) {
+		let reply = format!("{}:You have been marked as being away\r\n",
+			self.make_prefix(306));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whoreply(&mut self, entry: &WhoEntry) {
+		let reply = format!("{}{} {} {} {} {} {} :0 {}\r\n",
+			self.make_prefix(352),
+			entry.channel,
+			entry.user.user,
+			format_host(&entry.addr),
+			self.config().server_name,
+			entry.nick,
+			who_flags(entry),
+			entry.user.realname);
+		self.write_reply(reply);
+	}
+
+	/// RPL_WHOSPCRPL, carrying only the requested WHOX fields, in order.
+	fn send_rpl_whospcrpl(&mut self, entry: &WhoEntry, whox: &str) {
+		let (fields, token) = match whox.find(',') {
+			Some(ix) => (&whox[..ix], &whox[ix+1..]),
+			None => (whox, "0"),
+		};
+		let mut values = vec![];
+		for field in WHOX_FIELDS.chars().filter(|&field| fields.contains(field)) {
+			values.push(match field {
+				't' => token.to_string(),
+				'c' => entry.channel.clone(),
+				'u' => entry.user.user.clone(),
+				'i' | 'h' => format_host(&entry.addr),
+				's' => self.config().server_name.clone(),
+				'n' => entry.nick.clone(),
+				'f' => who_flags(entry),
+				'd' => "0".to_string(),
+				'l' => unix_timestamp().saturating_sub(entry.user.last_active).to_string(),
+				'a' => "0".to_string(), // no account system, so never logged in
+				'o' => "n/a".to_string(),
+				_ => format!(":{}", entry.user.realname),
+			});
+		}
+		let reply = format!("{}{}\r\n",
+			self.make_prefix(354),
+			values.join(" "));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_endofwho(&mut self, mask: &str) {
+		let reply = format!("{}{} :End of WHO list\r\n",
+			self.make_prefix(315),
+			mask);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whoisuser(&mut self, nick: &str, user: &User, addr: SocketAddr) {
 		let reply = format!("{}{} {} {} * :{}\r\n",
 			self.make_prefix(311),
 			nick,
 			user.user,
-			host,
+			format_host(&addr),
 			user.realname);
 		self.write_reply(reply);
 	}
 
-	fn send_rpl_whoisserver(&mut self, nick: String, host:SocketAddr) {
-		let reply = format!("{}{} {} :server info\r\n",
+	fn send_rpl_whoisserver(&mut self, nick: &str) {
+		let reply = format!("{}{} {} :{}\r\n",
 			self.make_prefix(312),
 			nick,
-			host);
+			self.config().server_name,
+			self.config().description);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whoisoperator(&mut self, nick: &str) {
+		let reply = format!("{}{} :is an IRC operator\r\n",
+			self.make_prefix(313),
+			nick);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whoissecure(&mut self, nick: &str) {
+		let reply = format!("{}{} :is using a secure connection\r\n",
+			self.make_prefix(671),
+			nick);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whoisidle(&mut self, nick: &str, user: &User) {
+		let reply = format!("{}{} {} {} :seconds idle, signon time\r\n",
+			self.make_prefix(317),
+			nick,
+			unix_timestamp().saturating_sub(user.last_active),
+			user.signon);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_whowasuser(&mut self, entry: &WhowasEntry) {
+		let reply = format!("{}{} {} {} * :{}\r\n",
+			self.make_prefix(314),
+			entry.nick,
+			entry.user,
+			entry.host,
+			entry.realname);
+		self.write_reply(reply);
+	}
+
+	/// RPL_WHOISSERVER as sent for WHOWAS, with the time the nick was left.
+	fn send_rpl_whowasserver(&mut self, entry: &WhowasEntry) {
+		let reply = format!("{}{} {} :{}\r\n",
+			self.make_prefix(312),
+			entry.nick,
+			entry.server,
+			format_timestamp(entry.departed_at));
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_endofwhowas(&mut self, nick: &str) {
+		let reply = format!("{}{} :End of WHOWAS\r\n",
+			self.make_prefix(369),
+			nick);
 		self.write_reply(reply);
 	}
 
@@ -389,9 +1881,133 @@ impl Connection {
 		self.write_reply(reply);
 	}
 
+	fn send_rpl_whoischannels(&mut self, nick: &str, channels: &str) {
+		let reply = format!("{}{} :{}\r\n",
+			self.make_prefix(319),
+			nick,
+			channels);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_channelmodeis(&mut self, channel: &str, mode_string: &str) {
+		let reply = format!("{}{} {}\r\n",
+			self.make_prefix(324),
+			channel,
+			mode_string);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_creationtime(&mut self, channel: &str, created_at: u64) {
+		let reply = format!("{}{} {}\r\n",
+			self.make_prefix(329),
+			channel,
+			created_at);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_notopic(&mut self, channel: &str) {
+		let reply = format!("{}{} :No topic is set\r\n",
+			self.make_prefix(331),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_topic(&mut self, channel: &str, topic: &str) {
+		let reply = format!("{}{} :{}\r\n",
+			self.make_prefix(332),
+			channel,
+			topic);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_topicwhotime(&mut self, channel: &str, set_by: &str, set_at: u64) {
+		let reply = format!("{}{} {} {}\r\n",
+			self.make_prefix(333),
+			channel,
+			set_by,
+			set_at);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_inviting(&mut self, nick: &str, channel: &str) {
+		let reply = format!("{}{} {}\r\n",
+			self.make_prefix(341),
+			nick,
+			channel);
+		self.write_reply(reply);
+	}
+
+	/// Send names across as many RPL_NAMREPLY lines as needed.
+	fn send_rpl_namreplies(&mut self, symbol: char, channel: &str, names: Vec<String>) {
+		for line in wrap_words(names) {
+			self.send_rpl_namreply(symbol, channel, &line);
+		}
+	}
+
+	fn send_rpl_namreply(&mut self, symbol: char, channel: &str, names: &str) {
+		let reply = format!("{}{} {} :{}\r\n",
+			self.make_prefix(353),
+			symbol,
+			channel,
+			names);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_endofnames(&mut self, channel: &str) {
+		let reply = format!("{}{} :End of NAMES list\r\n",
+			self.make_prefix(366),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_list(&mut self, channel: &str, num_users: usize, topic: &str) {
+		let reply = format!("{}{} {} :{}\r\n",
+			self.make_prefix(322),
+			channel,
+			num_users,
+			topic);
+		self.write_reply(reply);
+	}
+
+	fn send_rpl_listend(&mut self) {
+		let reply = format!("{}:End of LIST\r\n",
+			self.make_prefix(323));
+		self.write_reply(reply);
+	}
+
+	fn send_err_wasnosuchnick(&mut self, nickname: &str) {
+		let reply = format!("{}{} :There was no such nickname\r\n",
+			self.make_prefix(406),
+			nickname);
+		self.write_reply(reply);
+	}
+
+	fn send_err_noorigin(&mut self) {
+		let reply = format!(":{} 409 {} :No origin specified\r\n",
+				self.config().server_name,
+				self.get_nickname_or_star());
+		self.write_reply(reply);
+	}
+
+	fn send_err_nonicknamegiven(&mut self) {
+		let reply = format!(":{} 431 {} :No nickname given\r\n",
+				self.config().server_name,
+				self.get_nickname_or_star());
+		self.write_reply(reply);
+	}
+
+	fn send_err_erroneusnickname(&mut self, nickname: &str) {
+		let reply = format!(":{} 432 {} {} :Erroneous nickname\r\n",
+				self.config().server_name,
+				self.get_nickname_or_star(),
+				nickname);
+		self.write_reply(reply);
+	}
+
 	fn send_err_nicknameinuse(&mut self, nickname: String) {
-		let reply = format!(":{} 433 * {} :Nickname is already in use\r\n",
-				self.local_addr,
+		let reply = format!(":{} 433 {} {} :Nickname is already in use\r\n",
+				self.config().server_name,
+				self.get_nickname_or_star(),
 				nickname);
 		self.write_reply(reply);
 	}
@@ -403,12 +2019,152 @@ impl Connection {
 		self.write_reply(reply);
 	}
 
+	fn send_err_nosuchserver(&mut self, server: &str) {
+		let reply = format!("{}{} :No such server\r\n",
+			self.make_prefix(402),
+			server);
+		self.write_reply(reply);
+	}
+
+	fn send_err_nosuchchannel(&mut self, channel: String) {
+		let reply = format!("{}{} :No such channel\r\n",
+			self.make_prefix(403),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_cannotsendtochan(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot send to channel\r\n",
+			self.make_prefix(404),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_usernotinchannel(&mut self, nickname: String, channel: &str) {
+		let reply = format!("{}{} {} :They aren't on that channel\r\n",
+			self.make_prefix(441),
+			nickname,
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_notonchannel(&mut self, channel: String) {
+		let reply = format!("{}{} :You're not on that channel\r\n",
+			self.make_prefix(442),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_useronchannel(&mut self, nickname: String, channel: &str) {
+		let reply = format!("{}{} {} :is already on channel\r\n",
+			self.make_prefix(443),
+			nickname,
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_channelisfull(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot join channel (+l)\r\n",
+			self.make_prefix(471),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_unknownmode(&mut self, mode: char, channel: &str) {
+		let reply = format!("{}{} :is unknown mode char to me for {}\r\n",
+			self.make_prefix(472),
+			mode,
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_secureonlychan(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot join channel (+S)\r\n",
+			self.make_prefix(489),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_inviteonlychan(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot join channel (+i)\r\n",
+			self.make_prefix(473),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_bannedfromchan(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot join channel (+b)\r\n",
+			self.make_prefix(474),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_badchannelkey(&mut self, channel: String) {
+		let reply = format!("{}{} :Cannot join channel (+k)\r\n",
+			self.make_prefix(475),
+			channel);
+		self.write_reply(reply);
+	}
+
+	fn send_err_passwdmismatch(&mut self) {
+		let reply = format!("{}:Password incorrect\r\n",
+			self.make_prefix(464));
+		self.write_reply(reply);
+	}
+
+	fn send_err_yourebannedcreep(&mut self, reason: &str) {
+		let reply = format!("{}:You are banned from this server: {}\r\n",
+			self.make_prefix(465),
+			reason);
+		self.write_reply(reply);
+	}
+
+	fn send_err_noprivileges(&mut self) {
+		let reply = format!("{}:Permission Denied- You're not an IRC operator\r\n",
+			self.make_prefix(481));
+		self.write_reply(reply);
+	}
+
+	fn send_err_umodeunknownflag(&mut self) {
+		let reply = format!("{}:Unknown MODE flag\r\n",
+			self.make_prefix(501));
+		self.write_reply(reply);
+	}
+
+	fn send_err_usersdontmatch(&mut self) {
+		let reply = format!("{}:Cannot change mode for other users\r\n",
+			self.make_prefix(502));
+		self.write_reply(reply);
+	}
+
+	fn send_err_banlistfull(&mut self, channel: &str, mode: char) {
+		let reply = format!("{}{} {} :Channel list is full\r\n",
+			self.make_prefix(478),
+			channel,
+			mode);
+		self.write_reply(reply);
+	}
+
+	fn send_err_chanoprivsneeded(&mut self, channel: String) {
+		let reply = format!("{}{} :You're not channel operator\r\n",
+			self.make_prefix(482),
+			channel);
+		self.write_reply(reply);
+	}
+
 	fn send_err_nomotd(&mut self) {
 		let reply = format!("{}:MOTD File is missing\r\n",
 			self.make_prefix(422));
 		self.write_reply(reply);
 	}
 
+	fn send_err_needmoreparams(&mut self, command: &str) {
+		let reply = format!("{}{} :Not enough parameters\r\n",
+			self.make_prefix(461),
+			command);
+		self.write_reply(reply);
+	}
+
 	fn send_err_unknowncommand(&mut self, cmd: String) {
 		let reply = format!("{}{} :Unknown command\r\n",
 			self.make_prefix(421),
@@ -416,46 +2172,277 @@ impl Connection {
 		self.write_reply(reply);
 	}
 
-	// fn send_err_alreadyregistered(&mut self) {
-	// 	let reply = format!(":{} 462 :Unauthorized command (already registered)\r\n",
-	// 			self.local_addr);
-	// 	self.write_reply(reply);
-	// }
+	fn send_err_notregistered(&mut self) {
+		let reply = format!("{}:You have not registered\r\n",
+			self.make_prefix(451));
+		self.write_reply(reply);
+	}
+
+	fn send_err_alreadyregistred(&mut self) {
+		let reply = format!("{}:Unauthorized command (already registered)\r\n",
+			self.make_prefix(462));
+		self.write_reply(reply);
+	}
+
+	fn send_err_invalidcapcmd(&mut self, subcommand: &str) {
+		let reply = format!("{}{} :Invalid CAP command\r\n",
+			self.make_prefix(410),
+			subcommand);
+		self.write_reply(reply);
+	}
+
+	fn send_cap(&mut self, subcommand: &str, caps: &str) {
+		let reply = format!(":{} CAP {} {} :{}\r\n",
+			self.config().server_name,
+			self.get_nickname_or_star(),
+			subcommand,
+			caps);
+		self.write_reply(reply);
+	}
 
 	fn make_prefix(&self, repl_code: u16) -> String {
-		format!(":{} {} {} ", self.local_addr, repl_code, self.get_nickname())
+		format!(":{} {:03} {} ", self.config().server_name, repl_code, self.get_nickname_or_star())
+	}
+
+	fn make_user_prefix(&self) -> String {
+		format!("{}!{}@{}", self.get_nickname(), self.get_user(), self.get_host())
+	}
+
+	/// The configuration as it stands, which REHASH may since have replaced.
+	fn config(&self) -> Arc<Config> {
+		self.config.lock().unwrap().clone()
+	}
+
+	/// The case-folded form of a nickname or channel name, for lookups.
+	fn fold(&self, name: &str) -> String {
+		self.config().casemapping.fold(name)
+	}
+
+	fn get_host(&self) -> String {
+		format_host(&self.peer_addr)
+	}
+
+	/// Reset our idle time, as reported by WHO and WHOIS.
+	fn mark_active(&self) {
+		let mut uu = self.users.lock().unwrap();
+		if let Some(user) = (*uu).get_mut(&self.peer_addr) {
+			user.last_active = unix_timestamp();
+		}
 	}
 
 	fn get_nickname(&self) -> String {
-		let result = self.my_nickname.clone().unwrap();
-		return result;
+		self.my_nickname.clone().unwrap()
+	}
+
+	/// Our nickname as the target of a numeric reply, which is `*` until
+	/// we have one.
+	fn get_nickname_or_star(&self) -> String {
+		self.my_nickname.clone().unwrap_or_else(|| "*".to_string())
+	}
+
+	fn is_operator(&self) -> bool {
+		let uu = self.users.lock().unwrap();
+		(*uu).get(&self.peer_addr).is_some_and(|user| user.operator)
 	}
 
 	fn get_user(&self) -> String {
 		let uu = self.users.lock().unwrap();
-		return (*uu)[&self.peer_addr].user.clone();
+		(*uu)[&self.peer_addr].user.clone()
 	}
 
 	fn get_num_users(&self) -> usize {
 		let n_users = self.num_known_users.lock().unwrap();
-		return n_users.clone();
+		*n_users
 	}
 
 	fn get_num_unknown(&self) -> usize {
-		return self.get_num_clients() - self.get_num_users();
+		self.get_num_clients() - self.get_num_users()
+	}
+
+	fn get_num_operators(&self) -> usize {
+		let uu = self.users.lock().unwrap();
+		(*uu).values().filter(|user| user.operator).count()
+	}
+
+	fn get_num_channels(&self) -> usize {
+		let cc = self.channels.lock().unwrap();
+		(*cc).len()
 	}
 
 	fn get_num_clients(&self) -> usize {
 		let pb = self.phonebook.lock().unwrap();
-		return (*pb).len();
+		(*pb).len()
+	}
+
+	fn get_away(&self, nick: &str) -> Option<String> {
+		let nn = self.nicknames.lock().unwrap();
+		let uu = self.users.lock().unwrap();
+		(*nn).get(self.fold(nick).as_str())
+			.and_then(|addr| (*uu).get(addr))
+			.and_then(|user| user.away.clone())
+	}
+
+	fn is_invisible(&self, nick: &str) -> bool {
+		let nn = self.nicknames.lock().unwrap();
+		let uu = self.users.lock().unwrap();
+		(*nn).get(self.fold(nick).as_str())
+			.and_then(|addr| (*uu).get(addr))
+			.is_some_and(|user| user.invisible)
+	}
+
+	/// Whether we may send to a channel under its +n and +m restrictions.
+	/// Returns None if no such channel exists.
+	fn can_speak_in(&self, name: &str) -> Option<bool> {
+		let nick = self.get_nickname();
+		let hostmask = self.make_user_prefix();
+		let cc = self.channels.lock().unwrap();
+		(*cc).get(self.fold(name).as_str()).map(|channel| channel.can_speak(&nick, &hostmask))
+	}
+
+	/// Deliver a message to another user through their phonebook entry.
+	/// Returns false if no such nickname is registered.
+	fn send_to_nick(&self, nick: &str, message: String) -> bool {
+		let nn = self.nicknames.lock().unwrap();
+		let pb = self.phonebook.lock().unwrap();
+		match (*nn).get(self.fold(nick).as_str()).and_then(|addr| (*pb).get(addr)) {
+			Some(target_queue) => {
+				target_queue.push(&format!("{}\r\n", message));
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Deliver a message to every member of a channel except ourselves.
+	/// Returns false if no such channel exists.
+	fn send_to_channel(&self, channel: &str, message: String) -> bool {
+		self.send_to_channel_status(channel, None, message)
+	}
+
+	/// As `send_to_channel`, but only to members holding the rank of a
+	/// STATUSMSG prefix, if one is given.
+	fn send_to_channel_status(&self, channel: &str, status: Option<char>, message: String) -> bool {
+		let my_nick = self.fold(&self.get_nickname());
+		let members : Vec<String>;
+		{
+			let cc = self.channels.lock().unwrap();
+			members = match (*cc).get(self.fold(channel).as_str()) {
+				Some(channel) => channel.members.iter()
+					.filter(|&(member, _)| member.folded() != my_nick)
+					.filter(|&(_, membership)| status.is_none_or(|prefix| membership.has_status(prefix)))
+					.map(|(member, _)| member.to_string())
+					.collect(),
+				None => { return false; },
+			};
+		}
+		for member in members {
+			self.send_to_nick(&member, message.clone());
+		}
+		true
+	}
+
+	/// Deliver a message once to every user sharing a channel with us.
+	fn send_to_peers(&self, message: String) {
+		let my_nick = self.fold(&self.get_nickname());
+		let mut peers : HashSet<CaseKey> = HashSet::new();
+		{
+			let cc = self.channels.lock().unwrap();
+			for channel in (*cc).values().filter(|channel| channel.is_member(&my_nick)) {
+				peers.extend(channel.members.keys()
+					.filter(|&member| member.folded() != my_nick)
+					.cloned());
+			}
+		}
+		for peer in peers {
+			self.send_to_nick(peer.as_str(), message.clone());
+		}
+	}
+
+	/// Text spanning several lines, such as a TOML parse error, goes out as
+	/// one NOTICE per line.
+	fn send_server_notice(&mut self, text: &str) {
+		for line in text.lines().filter(|line| !line.trim().is_empty()) {
+			let reply = format!(":{} NOTICE {} :{}\r\n",
+				self.config().server_name,
+				self.get_nickname_or_star(),
+				line);
+			self.write_reply(reply);
+		}
 	}
 
 	fn write_reply(&mut self, reply: String) {
-		if let Err(e) = self.stream.write(reply.as_bytes()) {
-			error!("Stream Write Error: {}", e);
+		self.sendq.push(&reply);
+	}
+}
+
+/// Lets the reactor register a connection by its socket.
+impl Source for Connection {
+	fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+		self.stream.register(registry, token, interests)
+	}
+
+	fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+		self.stream.reregister(registry, token, interests)
+	}
+
+	fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+		self.stream.deregister(registry)
+	}
+}
+
+/// Join words with spaces into as few lines as fit within MAX_NAMES_LEN.
+fn wrap_words(words: Vec<String>) -> Vec<String> {
+	let mut lines = vec![];
+	let mut line = String::new();
+	for word in words {
+		if !line.is_empty() && line.len() + word.len() > MAX_NAMES_LEN {
+			lines.push(line);
+			line = String::new();
+		}
+		if !line.is_empty() {
+			line.push(' ');
 		}
-		if let Err(e) = self.stream.flush() {
-			error!("Stream Flush Error: {}", e);
+		line.push_str(&word);
+	}
+	if !line.is_empty() {
+		lines.push(line);
+	}
+	lines
+}
+
+/// A client's address as shown in its hostmask. IPv4 clients reaching an
+/// IPv6 listener are shown by their IPv4 address, and an IPv6 address
+/// starting with a colon gets a leading 0 so it cannot be read as the
+/// start of a trailing parameter.
+pub fn format_host(addr: &SocketAddr) -> String {
+	let ip = match addr.ip() {
+		IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+		ip => ip,
+	};
+	let host = ip.to_string();
+	if host.starts_with(':') {
+		format!("0{}", host)
+	} else {
+		host
+	}
+}
+
+/// H (here) or G (gone), then * for operators and the channel prefix.
+fn who_flags(entry: &WhoEntry) -> String {
+	format!("{}{}{}",
+		if entry.user.away.is_some() { "G" } else { "H" },
+		if entry.user.operator { "*" } else { "" },
+		entry.prefix)
+}
+
+/// Cut text down to at most `max_len` bytes without splitting a character.
+fn truncate_text(mut text: String, max_len: usize) -> String {
+	if text.len() > max_len {
+		let mut end = max_len;
+		while !text.is_char_boundary(end) {
+			end -= 1;
 		}
+		text.truncate(end);
 	}
-}
\ No newline at end of file
+	text
+}
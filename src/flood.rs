@@ -0,0 +1,77 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Fakelag, after the classic ircd scheme: every command a client sends
+/// moves its penalty clock on by the command's cost. While the clock runs
+/// more than the burst allowance ahead of real time, further commands wait
+/// in the client's RecvQ.
+pub struct FloodControl {
+	clock: Instant,
+	burst: Duration,
+}
+
+impl FloodControl {
+	pub fn new(burst: Duration) -> Self {
+		FloodControl {clock: Instant::now(), burst}
+	}
+
+	pub fn set_burst(&mut self, burst: Duration) {
+		self.burst = burst;
+	}
+
+	/// Whether another command may be processed at `now`.
+	pub fn is_ready(&self, now: Instant) -> bool {
+		self.clock <= now + self.burst
+	}
+
+	pub fn charge(&mut self, now: Instant, cost: Duration) {
+		self.clock = cmp::max(self.clock, now) + cost;
+	}
+
+	/// When the next command may be processed.
+	pub fn ready_at(&self) -> Instant {
+		self.clock.checked_sub(self.burst).unwrap_or(self.clock)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn allows_a_burst_then_holds_back() {
+		let mut flood = FloodControl::new(Duration::from_secs(10));
+		let now = Instant::now();
+		for _ in 0..5 {
+			assert!(flood.is_ready(now));
+			flood.charge(now, Duration::from_secs(2));
+		}
+		// the clock is now exactly the burst ahead, which is still allowed
+		assert!(flood.is_ready(now));
+		flood.charge(now, Duration::from_secs(2));
+		assert!(!flood.is_ready(now));
+		assert!(flood.is_ready(now + Duration::from_secs(2)));
+		assert_eq!(flood.ready_at(), flood.clock - Duration::from_secs(10));
+	}
+
+	#[test]
+	fn idle_time_is_not_banked() {
+		let mut flood = FloodControl::new(Duration::from_secs(4));
+		let later = Instant::now() + Duration::from_secs(60);
+		flood.charge(later, Duration::from_secs(2));
+		flood.charge(later, Duration::from_secs(2));
+		flood.charge(later, Duration::from_secs(2));
+		assert!(!flood.is_ready(later));
+		assert_eq!(flood.ready_at(), later + Duration::from_secs(2));
+	}
+
+	#[test]
+	fn burst_can_change() {
+		let mut flood = FloodControl::new(Duration::from_secs(10));
+		let now = Instant::now();
+		flood.charge(now, Duration::from_secs(5));
+		assert!(flood.is_ready(now));
+		flood.set_burst(Duration::from_secs(1));
+		assert!(!flood.is_ready(now));
+	}
+}
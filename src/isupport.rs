@@ -0,0 +1,113 @@
+use channel;
+use config::Config;
+
+/// Clients handle at most this many tokens in one RPL_ISUPPORT line.
+const MAX_TOKENS_PER_LINE: usize = 13;
+
+/// A feature that advertises itself in RPL_ISUPPORT. New features add
+/// their tokens by appending a function to `FEATURES`.
+type Feature = fn(&Config, &mut ISupport);
+
+const FEATURES: &[Feature] = &[
+	server_tokens,
+	channel::isupport_tokens,
+];
+
+/// The tokens sent in RPL_ISUPPORT, in the order they were added.
+#[derive(Default)]
+pub struct ISupport {
+	tokens: Vec<String>,
+}
+
+impl ISupport {
+	/// Gather the tokens of every feature for the given configuration.
+	pub fn new(config: &Config) -> Self {
+		let mut isupport = ISupport::default();
+		for feature in FEATURES {
+			feature(config, &mut isupport);
+		}
+		isupport
+	}
+
+	/// Advertise a parameterless token, such as `WHOX`.
+	pub fn add_flag(&mut self, name: &str) {
+		self.tokens.push(name.to_string());
+	}
+
+	/// Advertise a `NAME=value` token. Characters the grammar forbids in
+	/// values are sent as `\xHH` escapes.
+	pub fn add(&mut self, name: &str, value: &str) {
+		let mut token = format!("{}=", name);
+		for c in value.chars() {
+			match c {
+				' ' | '\\' | '=' => { token.push_str(&format!("\\x{:02X}", c as u32)); },
+				other => { token.push(other); },
+			}
+		}
+		self.tokens.push(token);
+	}
+
+	/// The tokens joined into as many lines as the per-line limit needs.
+	pub fn lines(&self) -> Vec<String> {
+		self.tokens.chunks(MAX_TOKENS_PER_LINE)
+			.map(|chunk| chunk.join(" "))
+			.collect()
+	}
+}
+
+fn server_tokens(config: &Config, isupport: &mut ISupport) {
+	isupport.add("NETWORK", &config.network);
+	isupport.add("CASEMAPPING", config.casemapping.name());
+	isupport.add("NICKLEN", &config.max_nick_len.to_string());
+	isupport.add("AWAYLEN", &config.max_away_len.to_string());
+	isupport.add("KICKLEN", &config.max_kick_len.to_string());
+	isupport.add("TARGMAX", "JOIN:,PART:,NAMES:,LIST:,KICK:,WHOIS:,WHOWAS:,PRIVMSG:1,NOTICE:1");
+	isupport.add_flag("WHOX");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escapes_forbidden_value_characters() {
+		let mut isupport = ISupport::default();
+		isupport.add("NETWORK", "My Net=\\");
+		isupport.add_flag("WHOX");
+		assert_eq!(isupport.lines(), vec!["NETWORK=My\\x20Net\\x3D\\x5C WHOX"]);
+	}
+
+	#[test]
+	fn splits_lines_at_thirteen_tokens() {
+		let mut isupport = ISupport::default();
+		for i in 0..27 {
+			isupport.add_flag(&format!("T{}", i));
+		}
+		let lines = isupport.lines();
+		assert_eq!(lines.len(), 3);
+		assert_eq!(lines[0].split(' ').count(), 13);
+		assert_eq!(lines[1].split(' ').count(), 13);
+		assert_eq!(lines[2], "T26");
+		assert!(lines[0].starts_with("T0 T1 "));
+		assert!(lines[1].starts_with("T13 "));
+	}
+
+	#[test]
+	fn exactly_thirteen_tokens_fit_one_line() {
+		let mut isupport = ISupport::default();
+		for i in 0..13 {
+			isupport.add_flag(&format!("T{}", i));
+		}
+		assert_eq!(isupport.lines().len(), 1);
+		assert!(ISupport::default().lines().is_empty());
+	}
+
+	#[test]
+	fn gathers_every_feature() {
+		let lines = ISupport::new(&Config::default()).lines();
+		assert!(lines.iter().all(|line| line.split(' ').count() <= MAX_TOKENS_PER_LINE));
+		let tokens: Vec<&str> = lines.iter().flat_map(|line| line.split(' ')).collect();
+		assert!(tokens.contains(&"CASEMAPPING=rfc1459"));
+		assert!(tokens.contains(&"CHANTYPES=#&"));
+	}
+}
@@ -1,9 +1,34 @@
 #[macro_use]
 extern crate log;
-extern crate bufstream;
+extern crate bcrypt;
+extern crate unicode_normalization;
+extern crate mio;
+extern crate rustls;
+extern crate serde;
+extern crate toml;
+extern crate signal_hook;
+extern crate signal_hook_mio;
+#[cfg(test)]
+extern crate rcgen;
 
 mod parser;
 mod server;
 mod connection;
+mod casemap;
+mod channel;
+mod clock;
+mod config;
+mod flood;
+mod isupport;
+mod mask;
+mod mode;
+mod oper;
+mod rehash;
+mod sendq;
+mod tls;
+mod whowas;
 
-pub use server::IrcServer;
\ No newline at end of file
+pub use server::IrcServer;
+pub use oper::{Operator, hash_password};
+pub use config::{Config, Listener, AdminInfo, Kline};
+pub use casemap::CaseMapping;
\ No newline at end of file
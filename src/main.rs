@@ -6,16 +6,93 @@ extern crate rustirc;
 use getopts::Options;
 use std::env;
 use std::io::{Write};
+use std::net::SocketAddr;
 
-use rustirc::IrcServer;
+use rustirc::{IrcServer, Config, Listener, Operator, CaseMapping, hash_password};
 
 fn print_usage(program: &str, opts: Options) {
-    print!("{}", opts.usage(&brief(&program)));
+    print!("{}", opts.usage(&brief(program)));
 }
 
 fn brief<ProgramName>(program: ProgramName) -> String
         where ProgramName: std::fmt::Display {
-    return format!("Usage: {} -o PASSWD [-p PORT] [(-q|-v|--vv)]", program);
+    format!("Usage: {} -o [NAME:]PASSWD... [-p PORT] [(-q|-v|--vv)]\n       {} -c FILE [OPTIONS]\n       {} --mkpasswd PASSWD",
+        program, program, program)
+}
+
+/// The configuration file, if one was given, with the command line options
+/// applied over it.
+fn load_config(matches: &getopts::Matches) -> Result<Config, String> {
+    let mut config = match matches.opt_str("config") {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+    let mut listeners = vec![];
+    if let Some(s) = matches.opt_str("p") {
+        match s.parse::<u16>() {
+            Ok(p) => { listeners.push(Listener::new(SocketAddr::from(([127, 0, 0, 1], p)), false)); },
+            Err(_) => { return Err(format!("invalid port \"{}\"", s)); },
+        }
+    }
+    for (option, tls) in [("listen", false), ("tls-listen", true)].iter() {
+        for s in matches.opt_strs(option) {
+            match s.parse::<SocketAddr>() {
+                Ok(address) => { listeners.push(Listener::new(address, *tls)); },
+                Err(_) => { return Err(format!("invalid listen address \"{}\"", s)); },
+            }
+        }
+    }
+    config.operators.extend(matches.opt_strs("o").iter()
+        .map(|arg| Operator::from_arg(arg)));
+    if !listeners.is_empty() {
+        config.listeners = listeners;
+    }
+    if let Some(password) = matches.opt_str("password") {
+        config.password = Some(password);
+    }
+    if let Some(path) = matches.opt_str("tls-cert") {
+        config.tls_certificate = path;
+    }
+    if let Some(path) = matches.opt_str("tls-key") {
+        config.tls_private_key = path;
+    }
+    if let Some(s) = matches.opt_str("maxlist") {
+        match s.parse::<usize>() {
+            Ok(n) => { config.max_list_entries = n; },
+            Err(_) => { return Err(format!("invalid list size \"{}\"", s)); },
+        }
+    }
+    if let Some(s) = matches.opt_str("whowas") {
+        match s.parse::<usize>() {
+            Ok(n) => { config.whowas_length = n; },
+            Err(_) => { return Err(format!("invalid WHOWAS length \"{}\"", s)); },
+        }
+    }
+    if let Some(s) = matches.opt_str("nicklen") {
+        match s.parse::<usize>() {
+            Ok(n) if n > 0 => { config.max_nick_len = n; },
+            _ => { return Err(format!("invalid nickname length \"{}\"", s)); },
+        }
+    }
+    if let Some(s) = matches.opt_str("sendq") {
+        match s.parse::<usize>() {
+            Ok(n) if n > 0 => { config.classes[0].max_sendq = n; },
+            _ => { return Err(format!("invalid SendQ size \"{}\"", s)); },
+        }
+    }
+    if let Some(s) = matches.opt_str("pingfreq") {
+        match s.parse::<u64>() {
+            Ok(n) if n > 0 => { config.classes[0].ping_frequency_secs = n; },
+            _ => { return Err(format!("invalid ping frequency \"{}\"", s)); },
+        }
+    }
+    if let Some(s) = matches.opt_str("casemapping") {
+        match CaseMapping::from_name(&s) {
+            Some(mapping) => { config.casemapping = mapping; },
+            None => { return Err(format!("invalid case mapping \"{}\"", s)); },
+        }
+    }
+    Ok(config)
 }
 
 #[allow(unused_must_use)]
@@ -24,8 +101,30 @@ fn main() {
     let program = args[0].clone();
 
 	let mut opts = getopts::Options::new();
-	opts.reqopt("o", "", "operator password", "PASSWD");
-	opts.optopt("p", "port", "the port on which the server will listen", "PORT");
+	opts.optopt("c", "config", "read settings from a TOML file. Other options override \
+	             it, and -o adds to its operators", "FILE");
+	opts.optmulti("o", "", "operator block, named 'oper' if NAME is omitted. \
+	               PASSWD may be a bcrypt hash. May be repeated", "[NAME:]PASSWD");
+	opts.optopt("", "mkpasswd", "print a bcrypt hash of PASSWD for use with -o and exit", "PASSWD");
+	opts.optopt("p", "port", "the port on which the server will listen on 127.0.0.1", "PORT");
+	opts.optmulti("", "listen", "an address on which to accept clients, such as 0.0.0.0:6667 \
+	              or [::]:6667. May be repeated", "ADDRESS:PORT");
+	opts.optmulti("", "tls-listen", "an address on which to accept clients over TLS. \
+	              May be repeated", "ADDRESS:PORT");
+	opts.optopt("", "tls-cert", "the PEM certificate chain for TLS (default cert.pem)", "FILE");
+	opts.optopt("", "tls-key", "the PEM private key for TLS (default key.pem)", "FILE");
+	opts.optopt("", "password", "require clients to send PASSWD with PASS before \
+	             registering. May be a bcrypt hash", "PASSWD");
+	opts.optopt("", "maxlist", "the most entries in each channel ban, exception and \
+	             invite exception list", "N");
+	opts.optopt("", "whowas", "how many departed nicknames WHOWAS remembers", "N");
+	opts.optopt("", "nicklen", "the longest nickname users may take", "N");
+	opts.optopt("", "sendq", "the most bytes queued for a client before it is \
+	             disconnected", "BYTES");
+	opts.optopt("", "pingfreq", "how many seconds a client may stay silent before it is \
+	             sent a PING", "SECONDS");
+	opts.optopt("", "casemapping", "how nicknames and channel names are compared: ascii, \
+	             rfc1459 (the default), strict-rfc1459 or rfc7613", "MAPPING");
 	opts.optflag("q", "quiet", "quiet mode. No log messages will be printed");
 	opts.optflag("v", "", "print DEBUG messages");
 	opts.optflag("", "vv", "print TRACE messages");
@@ -34,7 +133,7 @@ fn main() {
 	let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
         Err(f) => {let message = format!("{}\n{}\n",
-                                  f.to_string(),
+                                  f,
                                   opts.usage(&brief(&args[0])));
             if let Err(err) = write!(std::io::stderr(), "{}", message) {
                 panic!("Failed to write to standard error: {}\n\
@@ -50,6 +149,29 @@ fn main() {
         print_usage(&program, opts);
         return;
     }
+    if let Some(password) = matches.opt_str("mkpasswd") {
+        match hash_password(&password) {
+            Ok(hash) => { println!("{}", hash); },
+            Err(e) => {
+                writeln!(std::io::stderr(), "Failed to hash password: {}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            writeln!(std::io::stderr(), "Invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    // a configuration file may leave out operators, but without one they
+    // have to come from the command line
+    if !matches.opt_present("c") && !matches.opt_present("o") {
+        write!(std::io::stderr(), "Required option 'o' missing\n{}\n", opts.usage(&brief(&program)));
+        std::process::exit(1);
+    }
     let mut logging_level = log::LogLevelFilter::Info;
     if matches.opt_present("v") {
         logging_level = log::LogLevelFilter::Debug;
@@ -61,20 +183,6 @@ fn main() {
         logging_level = log::LogLevelFilter::Off;
     }
 
-    let port = match matches.opt_str("p") {
-        Some(s) => s.parse::<u16>(),
-        None => "6667".parse::<u16>(),
-    };
-    let portnum;
-    match port {
-    	Ok(p) => {portnum = p;},
-    	Err(_) => {panic!("Invalid port");}
-    }
-    let op_passwd = match matches.opt_str("o") {
-        Some(s) => s,
-        None => "swordfish".to_string(),
-    };
-
     fern::Dispatch::new()
 	    .format(|out, message, record| {
 	        out.finish(format_args!(
@@ -87,11 +195,26 @@ fn main() {
 	    .chain(std::io::stdout())
 	    .apply();
 	
-    trace!("\nOperator Password: {}\nPort: {}", op_passwd, portnum);
+    trace!("\nOperators: {:?}\nListeners: {:?}", config.operators.iter().map(|o| &o.name).collect::<Vec<_>>(),
+        config.listeners.iter().map(|l| l.address).collect::<Vec<_>>());
+    if config.operators.is_empty() {
+        warn!("no operators are configured, so OPER will always fail");
+    }
     info!("INFO is printing.");
     debug!("DEBUG is printing.");
     trace!("TRACE is printing.");
 
-    let mut this_irc_server = IrcServer::new(portnum);
-    this_irc_server.run();
+    let mut this_irc_server = match IrcServer::new(config) {
+        Ok(server) => server,
+        Err(e) => {
+            writeln!(std::io::stderr(), "Invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    // REHASH reads the file again, and the options still take precedence
+    this_irc_server.reload_config_with(move || load_config(&matches));
+    if let Err(e) = this_irc_server.run() {
+        writeln!(std::io::stderr(), "{}", e);
+        std::process::exit(1);
+    }
 }
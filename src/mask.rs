@@ -0,0 +1,123 @@
+use casemap::{CaseMapping};
+
+/// Match text against an IRC wildcard pattern, where `*` matches any run of
+/// characters and `?` matches exactly one. Comparison ignores ASCII case.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let text: Vec<char> = text.chars().collect();
+	let (mut p, mut t) = (0, 0);
+	// position of the last `*` seen, and the text position it was tried at
+	let mut backtrack: Option<(usize, usize)> = None;
+
+	while t < text.len() {
+		if p < pattern.len() && pattern[p] == '*' {
+			backtrack = Some((p, t));
+			p += 1;
+		} else if p < pattern.len()
+			&& (pattern[p] == '?' || pattern[p].eq_ignore_ascii_case(&text[t])) {
+			p += 1;
+			t += 1;
+		} else if let Some((star_p, star_t)) = backtrack {
+			// let the last `*` swallow one more character and retry
+			p = star_p + 1;
+			t = star_t + 1;
+			backtrack = Some((star_p, star_t + 1));
+		} else {
+			return false;
+		}
+	}
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+pub fn is_wildcard(pattern: &str) -> bool {
+	pattern.contains(&['*', '?'][..])
+}
+
+/// Expand a partial hostmask to the full `nick!user@host` form, so that
+/// `bob` becomes `bob!*@*`, `bob@host` becomes `*!bob@host` and a bare
+/// host such as `*.example.com` becomes `*!*@*.example.com`.
+pub fn normalize_hostmask(mask: &str) -> String {
+	match (mask.find('!'), mask.find('@')) {
+		(Some(_), Some(_)) => mask.to_string(),
+		(Some(_), None) => format!("{}@*", mask),
+		(None, Some(_)) => format!("*!{}", mask),
+		(None, None) if mask.contains('.') || mask.contains(':') => format!("*!*@{}", mask),
+		(None, None) => format!("{}!*@*", mask),
+	}
+}
+
+/// Match a user's `nick!user@host` prefix against a (possibly partial)
+/// hostmask such as those in channel ban lists, with both compared as the
+/// casemapping folds them.
+pub fn hostmask_match(mask: &str, hostmask: &str, casemapping: CaseMapping) -> bool {
+	wildcard_match(&casemapping.fold(&normalize_hostmask(mask)), &casemapping.fold(hostmask))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn literal_match_ignores_ascii_case() {
+		assert!(wildcard_match("Alice", "alice"));
+		assert!(!wildcard_match("alice", "alicex"));
+		assert!(!wildcard_match("alicex", "alice"));
+	}
+
+	#[test]
+	fn star_matches_any_run() {
+		assert!(wildcard_match("*", ""));
+		assert!(wildcard_match("*", "anything"));
+		assert!(wildcard_match("a*e", "ae"));
+		assert!(wildcard_match("*!*@*.example.com", "bob!~b@host.example.com"));
+		assert!(!wildcard_match("*!*@*.example.com", "bob!~b@example.org"));
+	}
+
+	#[test]
+	fn star_backtracks() {
+		assert!(wildcard_match("*ab*ab", "xabyabab"));
+		assert!(wildcard_match("a*b*c", "aXbYbZc"));
+		assert!(!wildcard_match("a*b*c", "aXbYcZ"));
+	}
+
+	#[test]
+	fn question_mark_matches_one() {
+		assert!(wildcard_match("b?b", "bob"));
+		assert!(!wildcard_match("b?b", "bb"));
+		assert!(!wildcard_match("?", ""));
+	}
+
+	#[test]
+	fn detects_wildcards() {
+		assert!(is_wildcard("*.com"));
+		assert!(is_wildcard("b?b"));
+		assert!(!is_wildcard("bob"));
+	}
+
+	#[test]
+	fn normalizes_partial_hostmasks() {
+		assert_eq!(normalize_hostmask("bob"), "bob!*@*");
+		assert_eq!(normalize_hostmask("bob!~b"), "bob!~b@*");
+		assert_eq!(normalize_hostmask("bob@host"), "*!bob@host");
+		assert_eq!(normalize_hostmask("*.example.com"), "*!*@*.example.com");
+		assert_eq!(normalize_hostmask("2001:db8::1"), "*!*@2001:db8::1");
+		assert_eq!(normalize_hostmask("bob!~b@host"), "bob!~b@host");
+	}
+
+	#[test]
+	fn matches_partial_hostmasks() {
+		assert!(hostmask_match("bob", "bob!~b@host", CaseMapping::Ascii));
+		assert!(hostmask_match("*.example.com", "bob!~b@irc.example.com", CaseMapping::Ascii));
+		assert!(!hostmask_match("alice", "bob!~b@host", CaseMapping::Ascii));
+	}
+
+	#[test]
+	fn hostmasks_match_by_casemapping() {
+		assert!(hostmask_match("[bob]", "{BOB}!~b@host", CaseMapping::Rfc1459));
+		assert!(!hostmask_match("[bob]", "{bob}!~b@host", CaseMapping::Ascii));
+		assert!(hostmask_match("bob~*", "BOB^!~b@host", CaseMapping::Rfc1459));
+		assert!(!hostmask_match("bob~*", "bob^!~b@host", CaseMapping::StrictRfc1459));
+		assert!(hostmask_match("ÉLISE*", "élise!~e@host", CaseMapping::Rfc7613));
+		assert!(!hostmask_match("ÉLISE*", "élise!~e@host", CaseMapping::Rfc1459));
+	}
+}
@@ -0,0 +1,132 @@
+/// A single `+x`/`-x` change parsed out of a MODE command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeChange {
+	pub adding: bool,
+	pub mode: char,
+	pub param: Option<String>,
+}
+
+impl ModeChange {
+	pub fn new(adding: bool, mode: char, param: Option<String>) -> Self {
+		ModeChange {adding, mode, param}
+	}
+}
+
+/// Split a mode string such as `+ov-k alice bob key` into individual
+/// changes. `takes_param` decides, given the direction and mode letter,
+/// whether the change consumes the next parameter. A change that wants a
+/// parameter but has none left gets `None`, and the caller decides whether
+/// that is an error.
+pub fn parse_mode_changes<F>(modestring: &str, params: &[String], takes_param: F) -> Vec<ModeChange>
+		where F: Fn(bool, char) -> bool {
+	let mut changes = vec![];
+	let mut params = params.iter();
+	let mut adding = true;
+	for mode in modestring.chars() {
+		match mode {
+			'+' => { adding = true; },
+			'-' => { adding = false; },
+			_ => {
+				let param = if takes_param(adding, mode) {
+					params.next().cloned()
+				} else {
+					None
+				};
+				changes.push(ModeChange::new(adding, mode, param));
+			},
+		}
+	}
+	changes
+}
+
+/// Render changes back into a single mode string with trailing parameters,
+/// as sent in MODE broadcasts.
+pub fn format_mode_changes(changes: &[ModeChange]) -> String {
+	let mut modes = String::new();
+	let mut params = vec![];
+	let mut adding = None;
+	for change in changes {
+		if adding != Some(change.adding) {
+			modes.push(if change.adding { '+' } else { '-' });
+			adding = Some(change.adding);
+		}
+		modes.push(change.mode);
+		if let Some(ref param) = change.param {
+			params.push(param.clone());
+		}
+	}
+	if params.is_empty() {
+		modes
+	} else {
+		format!("{} {}", modes, params.join(" "))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use channel::{mode_takes_param};
+
+	fn params(list: &[&str]) -> Vec<String> {
+		list.iter().map(|s| s.to_string()).collect()
+	}
+
+	#[test]
+	fn parses_mixed_directions_and_params() {
+		let changes = parse_mode_changes("+ov-kl", &params(&["alice", "bob", "key"]), mode_takes_param);
+		assert_eq!(changes, vec![
+			ModeChange::new(true, 'o', Some("alice".to_string())),
+			ModeChange::new(true, 'v', Some("bob".to_string())),
+			ModeChange::new(false, 'k', Some("key".to_string())),
+			ModeChange::new(false, 'l', None),
+		]);
+	}
+
+	#[test]
+	fn list_modes_take_a_mask() {
+		let changes = parse_mode_changes("+eI-b", &params(&["*!*@good", "*!*@friend"]), mode_takes_param);
+		assert_eq!(changes, vec![
+			ModeChange::new(true, 'e', Some("*!*@good".to_string())),
+			ModeChange::new(true, 'I', Some("*!*@friend".to_string())),
+			// a list mode without a mask asks to see the list
+			ModeChange::new(false, 'b', None),
+		]);
+	}
+
+	#[test]
+	fn direction_defaults_to_adding() {
+		let changes = parse_mode_changes("it", &[], mode_takes_param);
+		assert_eq!(changes, vec![ModeChange::new(true, 'i', None), ModeChange::new(true, 't', None)]);
+	}
+
+	#[test]
+	fn missing_param_is_none() {
+		let changes = parse_mode_changes("+oo", &params(&["alice"]), mode_takes_param);
+		assert_eq!(changes[0].param, Some("alice".to_string()));
+		assert_eq!(changes[1].param, None);
+	}
+
+	#[test]
+	fn formats_with_trailing_params() {
+		let changes = vec![
+			ModeChange::new(true, 'o', Some("alice".to_string())),
+			ModeChange::new(true, 'l', Some("10".to_string())),
+			ModeChange::new(false, 'i', None),
+			ModeChange::new(false, 'v', Some("bob".to_string())),
+		];
+		assert_eq!(format_mode_changes(&changes), "+ol-iv alice 10 bob");
+	}
+
+	#[test]
+	fn formats_without_params() {
+		let changes = vec![ModeChange::new(false, 'm', None), ModeChange::new(false, 'n', None)];
+		assert_eq!(format_mode_changes(&changes), "-mn");
+		assert_eq!(format_mode_changes(&[]), "");
+	}
+
+	#[test]
+	fn round_trips() {
+		let changes = parse_mode_changes("+k-l+b-k", &params(&["key", "*!*@spam", "key"]), mode_takes_param);
+		assert_eq!(format_mode_changes(&changes), "+k-l+b-k key *!*@spam key");
+	}
+}
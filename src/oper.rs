@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bcrypt;
+use mio::{Token, Waker};
+
+use sendq::{PendingWrites};
+
+/// How long after one password check against a bcrypt hash the next from
+/// the same address may start.
+const HASH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A named operator block. The password may be given in plaintext or as a
+/// bcrypt hash (as printed by `--mkpasswd`).
+#[derive(Clone, Debug)]
+pub struct Operator {
+	pub name: String,
+	password: String,
+}
+
+impl Operator {
+	pub fn new(name: String, password: String) -> Self {
+		Operator {name, password}
+	}
+
+	/// Parse a `[NAME:]PASSWD` command line argument. Without a name the
+	/// block is called `oper`.
+	pub fn from_arg(arg: &str) -> Self {
+		match arg.find(':') {
+			Some(ix) if !is_bcrypt_hash(arg) => Operator::new(arg[..ix].to_string(), arg[ix+1..].to_string()),
+			_ => Operator::new("oper".to_string(), arg.to_string()),
+		}
+	}
+
+	/// Check a password given with OPER for this block. The name must
+	/// already have been found to match.
+	pub fn check(&self, password: &str, checker: &PasswordChecker, token: Token, from: IpAddr) -> PasswordCheck {
+		checker.check(password, &self.password, token, from)
+	}
+}
+
+/// The outcome of comparing a password, or where it will arrive if it is
+/// still being compared against a bcrypt hash.
+pub enum PasswordCheck {
+	Done(bool),
+	Pending(Receiver<bool>),
+}
+
+struct Job {
+	password: String,
+	hash: String,
+	not_before: Instant,
+	token: Token,
+	result: Sender<bool>,
+}
+
+/// Compares passwords against the configuration. Plaintext ones are
+/// compared at once, but checking a bcrypt hash takes long enough to hold
+/// up every client, so that is done on a worker thread. It sends the
+/// result back to the connection that asked, marks the connection as
+/// having pending writes and wakes the reactor to act on it.
+#[derive(Clone)]
+pub struct PasswordChecker {
+	jobs: Sender<Job>,
+	throttle: Arc<Mutex<HashThrottle>>,
+}
+
+impl PasswordChecker {
+	pub fn spawn(pending: PendingWrites, waker: Waker) -> Self {
+		let (jobs, received) = mpsc::channel();
+		thread::spawn(move || check_hashes(received, pending, waker));
+		PasswordChecker {jobs, throttle: Arc::new(Mutex::new(HashThrottle::default()))}
+	}
+
+	/// Compare a password from the client at `from`, whose connection is
+	/// `token`, against one from the configuration, which may be plaintext
+	/// or a bcrypt hash.
+	pub fn check(&self, password: &str, expected: &str, token: Token, from: IpAddr) -> PasswordCheck {
+		if !is_bcrypt_hash(expected) {
+			return PasswordCheck::Done(constant_time_eq(password.as_bytes(), expected.as_bytes()));
+		}
+		let not_before = self.throttle.lock().unwrap().reserve(from);
+		if not_before > Instant::now() {
+			debug!("delaying a password check from {}", from);
+		}
+		let (result, outcome) = mpsc::channel();
+		let job = Job {password: password.to_string(), hash: expected.to_string(), not_before, token, result};
+		if self.jobs.send(job).is_err() {
+			error!("the password checking thread has stopped");
+			return PasswordCheck::Done(false);
+		}
+		PasswordCheck::Pending(outcome)
+	}
+}
+
+/// The worker thread: checks each job once its throttle allows, earliest
+/// first, until the server is gone.
+fn check_hashes(jobs: Receiver<Job>, pending: PendingWrites, waker: Waker) {
+	let mut waiting : BTreeMap<(Instant, u64), Job> = BTreeMap::new();
+	let mut received = 0;
+	loop {
+		let job = match waiting.keys().next() {
+			None => match jobs.recv() {
+				Ok(job) => Some(job),
+				Err(_) => { return; },
+			},
+			Some(&(at, _)) => match jobs.recv_timeout(at.saturating_duration_since(Instant::now())) {
+				Ok(job) => Some(job),
+				Err(RecvTimeoutError::Timeout) => None,
+				Err(RecvTimeoutError::Disconnected) => { return; },
+			},
+		};
+		if let Some(job) = job {
+			// the count keeps jobs due at the same time apart, in order
+			waiting.insert((job.not_before, received), job);
+			received += 1;
+			continue;
+		}
+
+		let (_, job) = waiting.pop_first().unwrap();
+		let matched = bcrypt::verify(&job.password, &job.hash).unwrap_or(false);
+		// the connection may have closed in the meantime
+		if job.result.send(matched).is_ok() {
+			pending.lock().unwrap().push(job.token);
+			if let Err(e) = waker.wake() {
+				error!("Waker Error: {}", e);
+			}
+		}
+	}
+}
+
+pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
+	bcrypt::hash(password, bcrypt::DEFAULT_COST)
+}
+
+fn is_bcrypt_hash(password: &str) -> bool {
+	["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| password.starts_with(prefix))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Spaces out the password checks each address may have made against a
+/// bcrypt hash, so that no one client keeps the worker thread to itself.
+/// A check that comes too soon waits its turn rather than failing.
+#[derive(Default)]
+struct HashThrottle {
+	/// When each address may next have a check start.
+	next_check: HashMap<IpAddr, Instant>,
+}
+
+impl HashThrottle {
+	/// When a check for `from` may start: now, or once the interval since
+	/// its previous one has passed. The one after must wait for this one.
+	fn reserve(&mut self, from: IpAddr) -> Instant {
+		let now = Instant::now();
+		self.next_check.retain(|_, &mut at| at > now);
+		let address = throttled_address(from);
+		let start = self.next_check.get(&address).map_or(now, |&at| at.max(now));
+		self.next_check.insert(address, start + HASH_CHECK_INTERVAL);
+		start
+	}
+}
+
+/// IPv6 clients are throttled by /64, since each usually has a whole one.
+fn throttled_address(from: IpAddr) -> IpAddr {
+	match from {
+		IpAddr::V6(v6) if v6.to_ipv4_mapped().is_none() => {
+			let mut segments = v6.segments();
+			segments[4..].iter_mut().for_each(|segment| *segment = 0);
+			IpAddr::from(segments)
+		},
+		_ => from,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mio::{Events, Poll};
+
+	fn checker() -> (PasswordChecker, PendingWrites, Poll) {
+		let poll = Poll::new().unwrap();
+		let waker = Waker::new(poll.registry(), Token(0)).unwrap();
+		let pending = Arc::new(Mutex::new(vec![]));
+		(PasswordChecker::spawn(pending.clone(), waker), pending, poll)
+	}
+
+	fn outcome(check: PasswordCheck) -> bool {
+		match check {
+			PasswordCheck::Done(_) => panic!("a hash was checked on the calling thread"),
+			PasswordCheck::Pending(outcome) => outcome.recv().unwrap(),
+		}
+	}
+
+	#[test]
+	fn plaintext_is_checked_at_once() {
+		let (checker, _, _poll) = checker();
+		let from = IpAddr::from([192, 0, 2, 1]);
+		assert!(matches!(checker.check("secret", "secret", Token(7), from), PasswordCheck::Done(true)));
+		assert!(matches!(checker.check("secrets", "secret", Token(7), from), PasswordCheck::Done(false)));
+	}
+
+	#[test]
+	fn hashes_are_checked_on_the_worker() {
+		let (checker, pending, mut poll) = checker();
+		let hash = bcrypt::hash("secret", 4).unwrap();
+		let from = IpAddr::from([192, 0, 2, 1]);
+		assert!(outcome(checker.check("secret", &hash, Token(7), from)));
+		assert!(!outcome(checker.check("wrong", &hash, Token(8), IpAddr::from([192, 0, 2, 2]))));
+		// the reactor is woken once the connections are marked
+		let mut events = Events::with_capacity(1);
+		while pending.lock().unwrap().len() < 2 {
+			poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+			assert!(!events.is_empty());
+		}
+		assert_eq!(*pending.lock().unwrap(), vec![Token(7), Token(8)]);
+	}
+
+	#[test]
+	fn throttled_checks_are_delayed_not_failed() {
+		// as when a client registers with PASS and then sends OPER
+		let (checker, _, _poll) = checker();
+		let hash = bcrypt::hash("secret", 4).unwrap();
+		let from = IpAddr::from([192, 0, 2, 1]);
+		let started = Instant::now();
+		let pass = checker.check("secret", &hash, Token(7), from);
+		let oper = checker.check("secret", &hash, Token(7), from);
+		assert!(outcome(pass));
+		assert!(outcome(oper));
+		assert!(started.elapsed() >= HASH_CHECK_INTERVAL);
+	}
+
+	#[test]
+	fn throttle_spaces_out_each_address() {
+		let mut throttle = HashThrottle::default();
+		let now = Instant::now();
+		let first = throttle.reserve(IpAddr::from([192, 0, 2, 1]));
+		let second = throttle.reserve(IpAddr::from([192, 0, 2, 1]));
+		let third = throttle.reserve(IpAddr::from([192, 0, 2, 1]));
+		assert!(first >= now && first < now + HASH_CHECK_INTERVAL);
+		assert_eq!(second, first + HASH_CHECK_INTERVAL);
+		assert_eq!(third, second + HASH_CHECK_INTERVAL);
+		// other addresses are not held up
+		assert!(throttle.reserve(IpAddr::from([192, 0, 2, 2])) < second);
+	}
+
+	#[test]
+	fn ipv6_is_throttled_by_prefix() {
+		let mut throttle = HashThrottle::default();
+		let first = throttle.reserve("2001:db8::1".parse().unwrap());
+		assert_eq!(throttle.reserve("2001:db8::2".parse().unwrap()), first + HASH_CHECK_INTERVAL);
+		assert!(throttle.reserve("2001:db8:0:1::1".parse().unwrap()) < first + HASH_CHECK_INTERVAL);
+	}
+
+	#[test]
+	fn parses_operator_arguments() {
+		let named = Operator::from_arg("boss:pw");
+		assert_eq!((named.name.as_str(), named.password.as_str()), ("boss", "pw"));
+		let hash = "$2b$04$abcdefghijklmnopqrstuu5Jq3Wt0Yx6b0dXkkz1Q0bB4xq0lPNyy";
+		let unnamed = Operator::from_arg(hash);
+		assert_eq!((unnamed.name.as_str(), unnamed.password.as_str()), ("oper", hash));
+	}
+}
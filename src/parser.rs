@@ -1,17 +1,51 @@
+use mode::ModeChange;
+use clock::unix_timestamp;
+use casemap::CaseMapping;
+
 pub enum Command {
+	Pass(String), // password
+	Cap(String, Vec<String>), // subcommand, capabilities
 	Nick(String), // nickname
 	User(User), // user, mode, realname
 	Quit(String), // Quit Message
 	Privmsg(String, String), // msgtarget, msgtext
 	Notice(String, String), // msgtarget, msgtext
-	Ping,
+	Join(Vec<String>, Vec<String>), // channels, keys
+	Part(Vec<String>, Option<String>), // channels, part message
+	Topic(String, Option<String>), // channel, new topic
+	Names(Vec<String>), // channels
+	List(Vec<String>), // channel masks and conditions
+	Mode(String, Option<String>, Vec<String>), // target, modestring, mode params
+	Oper(String, String), // name, password
+	Kill(String, String), // nickname, comment
+	Rehash,
+	Kick(Vec<String>, Vec<String>, Option<String>), // channels, nicknames, comment
+	Invite(String, String), // nickname, channel
+	Away(Option<String>), // away message
+	Who(Option<String>, Option<String>), // mask, flags and WHOX fields
+	Ping(String), // token
 	Pong,
 	Motd,
 	Lusers,
-	Whois(String), // target
+	Admin(Option<String>), // server
+	Whois(Option<String>, Vec<String>), // server, targets
+	Whowas(Vec<String>, usize), // nicknames, count
+	Stats(Option<String>), // query
+	NeedMoreParams(String), // command
 	Unknown(String), // command
 }
 
+impl Command {
+	/// Whether a client may send this before completing registration.
+	pub fn allowed_unregistered(&self) -> bool {
+		match *self {
+			Command::NeedMoreParams(ref command) => matches!(command.as_str(), "PASS" | "CAP" | "USER"),
+			_ => matches!(*self, Command::Pass(_) | Command::Cap(..) | Command::Nick(_) | Command::User(_)
+				| Command::Quit(_) | Command::Ping(_) | Command::Pong),
+		}
+	}
+}
+
 struct Message {
 	prefix: Option<String>,
 	command: String,
@@ -24,34 +58,112 @@ impl Message {
 	}
 }
 
+pub const USER_MODES: &str = "aiowZ";
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct User {
 	pub user: String,
-	pub mode: String,
 	pub realname: String,
+	pub invisible: bool,
+	pub wallops: bool,
+	pub operator: bool,
+	/// Connected over TLS, shown as +Z.
+	pub secure: bool,
+	pub away: Option<String>,
+	pub last_active: u64,
+	pub signon: u64,
 }
 
 impl User {
+	/// `mode` is the RFC 2812 USER bitmask: bit 2 requests +w, bit 3 +i.
 	pub fn new(user: String, mode: String, realname: String) -> Self {
-		User {user: user, mode: mode, realname: realname}
+		let mode_bits = mode.parse::<u8>().unwrap_or(0);
+		User {
+			user,
+			realname,
+			invisible: mode_bits & 8 != 0,
+			wallops: mode_bits & 4 != 0,
+			operator: false,
+			secure: false,
+			away: None,
+			last_active: unix_timestamp(),
+			signon: unix_timestamp()}
+	}
+
+	pub fn mode_string(&self) -> String {
+		let mut modes = String::from("+");
+		if self.away.is_some() {
+			modes.push('a');
+		}
+		if self.invisible {
+			modes.push('i');
+		}
+		if self.operator {
+			modes.push('o');
+		}
+		if self.wallops {
+			modes.push('w');
+		}
+		if self.secure {
+			modes.push('Z');
+		}
+		modes
+	}
+
+	/// Apply one change requested through MODE, returning whether it took
+	/// effect or `Err` for an unknown flag. Operator status can only be
+	/// dropped here; it is granted by OPER. Away status is set by AWAY, and
+	/// +Z only reflects how the client connected.
+	pub fn apply_mode(&mut self, change: &ModeChange) -> Result<bool, char> {
+		let flag = match change.mode {
+			'i' => &mut self.invisible,
+			'w' => &mut self.wallops,
+			'o' if !change.adding => &mut self.operator,
+			'o' | 'a' | 'Z' => { return Ok(false); },
+			other => { return Err(other); },
+		};
+		let changed = *flag != change.adding;
+		*flag = change.adding;
+		Ok(changed)
 	}
 }
 
+/// RFC 2812 nickname grammar: a letter or special character, then letters,
+/// digits, specials and hyphens. Under rfc7613 the letters and digits may
+/// be any Unicode ones, as PRECIS identifiers allow. The length is counted
+/// in characters.
+pub fn is_valid_nickname(nick: &str, max_len: usize, casemapping: CaseMapping) -> bool {
+	let is_special = |c: char| "[]\\`_^{|}".contains(c);
+	let unicode = casemapping == CaseMapping::Rfc7613;
+	let is_letter = |c: char| c.is_ascii_alphabetic() || (unicode && !c.is_ascii() && c.is_alphabetic());
+	let is_letter_or_digit = |c: char| c.is_ascii_alphanumeric() || (unicode && !c.is_ascii() && c.is_alphanumeric());
+	let mut chars = nick.chars();
+	match chars.next() {
+		Some(first) if is_letter(first) || is_special(first) => {},
+		_ => { return false; },
+	}
+	nick.chars().count() <= max_len
+		&& chars.all(|c| is_letter_or_digit(c) || is_special(c) || c == '-')
+}
+
 fn parse_stream(stream: String) -> Result<Message, &'static str> {
-	let stream = stream.trim_right();
+	let stream = stream.trim_end();
 	let mut ix = 0;
 	let mut this_message = Message::new();
+	if stream.is_empty() {
+		return Err("empty message");
+	}
 	if stream.as_bytes()[0] == b':' {
 		if let Some(prefix_end) = stream.as_bytes().iter().position(|&c| c == b' ') {
 			trace!("scanned prefix");
 			this_message.prefix = Some(stream[1..prefix_end].to_string());
-			ix += prefix_end+1;
+			ix = prefix_end+1;
 			trace!("command at ix: {}", ix);
 		} else {
 			return Err("invalid prefix");
 		}
 	}
-	if let Some(command_end) = stream[ix..].as_bytes().iter().position(|&c| c == b' ') {
+	if let Some(command_end) = stream.as_bytes()[ix..].iter().position(|&c| c == b' ') {
 		this_message.command = stream[ix..ix+command_end].to_string();
 		trace!("scanned command: {}", this_message.command);
 		ix += command_end + 1;
@@ -63,12 +175,12 @@ fn parse_stream(stream: String) -> Result<Message, &'static str> {
 	}
 	while ix < stream.len() {
 		// long parameter
-		if stream[ix..].as_bytes()[0] == b':' {
+		if stream.as_bytes()[ix] == b':' {
 			trace!("scanned long param: {}", stream[ix+1..].to_string());
 			this_message.params.push(stream[ix+1..].to_string());
 			break;
 		}
-		if let Some(param_end) = stream[ix..].as_bytes().iter().position(|&c| c == b' ') {
+		if let Some(param_end) = stream.as_bytes()[ix..].iter().position(|&c| c == b' ') {
 			trace!("scanned param: {}", stream[ix..ix+param_end].to_string());
 			this_message.params.push(stream[ix..ix+param_end].to_string());
 			ix += param_end+1
@@ -81,6 +193,23 @@ fn parse_stream(stream: String) -> Result<Message, &'static str> {
 	Ok(this_message)
 }
 
+fn split_list(param: &str) -> Vec<String> {
+	param.split(',')
+		.filter(|item| !item.is_empty())
+		.map(|item| item.to_string())
+		.collect()
+}
+
+/// The command word of a raw message, looked at before parsing it fully.
+pub fn command_name(message: &str) -> &str {
+	let mut words = message.split_whitespace();
+	match words.next() {
+		Some(prefix) if prefix.starts_with(':') => words.next().unwrap_or(""),
+		Some(command) => command,
+		None => "",
+	}
+}
+
 pub fn parse_message(message: String) -> Result<Command, &'static str> {
 	debug!("\n\nmessage: {}", message);
 	
@@ -90,64 +219,229 @@ pub fn parse_message(message: String) -> Result<Command, &'static str> {
 	debug!("command: {}", this_message.command);
 	// debug!("msg has {} params", num_param);
 	match this_message.command.as_str() {
-		"NICK" => {
-			if num_param != 1 {
-				return Err("NICK needs 1 parameter");
+		"PASS" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
 			} else {
-				let this_nick = this_message.params[0].clone();
-				return Ok(Command::Nick(this_nick));
+				Ok(Command::Pass(this_message.params[0].clone()))
 			}
 		},
+		"CAP" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_caps = this_message.params.get(1)
+					.map(|caps| caps.split_whitespace().map(|cap| cap.to_string()).collect())
+					.unwrap_or_default();
+				Ok(Command::Cap(this_message.params[0].to_uppercase(), this_caps))
+			}
+		},
+		"NICK" => {
+			// a missing nickname is answered with ERR_NONICKNAMEGIVEN
+			let this_nick = this_message.params.first().cloned().unwrap_or_default();
+			Ok(Command::Nick(this_nick))
+		},
 		"USER" => {
-			if num_param != 4 {
-				return Err("USER needs 4 parameters");
+			if num_param < 4 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
 			} else {
-				return Ok(Command::User(
+				Ok(Command::User(
 					User::new(
 						this_message.params[0].to_string(),
 				 		this_message.params[1].to_string(),
 				 		this_message.params[3].to_string())
-					));
+					))
 			}
 		},
 		"QUIT" => {
 			if num_param == 0 {
-				return Ok(Command::Quit("Client Quit".to_string()));
+				Ok(Command::Quit("Client Quit".to_string()))
 			} else if num_param == 1 {
-				return Ok(Command::Quit(this_message.params[0].to_string()));
+				Ok(Command::Quit(this_message.params[0].to_string()))
 			} else {
-				return Err("Quit needs at most one parameter");
+				Err("Quit needs at most one parameter")
 			}
 		},
 		"PRIVMSG" => {
-			if num_param != 2 {
-				return Err("PRIVMSG needs 2 parameters");
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
 			} else {
 				let this_target = this_message.params[0].clone();
 				let this_text = this_message.params[1].clone();
-				return Ok(Command::Privmsg(this_target, this_text));
+				Ok(Command::Privmsg(this_target, this_text))
 			}
 		},
 		"NOTICE" => {
-			if num_param != 2 {
-				return Err("NOTICE needs 2 parameters");
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
 			} else {
 				let this_target = this_message.params[0].clone();
 				let this_text = this_message.params[1].clone();
-				return Ok(Command::Notice(this_target, this_text));
+				Ok(Command::Notice(this_target, this_text))
 			}
 		},
-		"PING" => { return Ok(Command::Ping); },
-		"PONG" => { return Ok(Command::Pong); },
-		"MOTD" => { return Ok(Command::Motd); },
-		"LUSERS" => { return Ok(Command::Lusers); },
+		"JOIN" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_keys = match this_message.params.get(1) {
+					Some(keys) => keys.split(',').map(|key| key.to_string()).collect(),
+					None => vec![],
+				};
+				Ok(Command::Join(split_list(&this_message.params[0]), this_keys))
+			}
+		},
+		"PART" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_reason = this_message.params.get(1).cloned();
+				Ok(Command::Part(split_list(&this_message.params[0]), this_reason))
+			}
+		},
+		"TOPIC" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_topic = this_message.params.get(1).cloned();
+				Ok(Command::Topic(this_message.params[0].clone(), this_topic))
+			}
+		},
+		"NAMES" => {
+			match this_message.params.first() {
+				Some(channels) => Ok(Command::Names(split_list(channels))),
+				None => Ok(Command::Names(vec![])),
+			}
+		},
+		"LIST" => {
+			match this_message.params.first() {
+				Some(conditions) => Ok(Command::List(split_list(conditions))),
+				None => Ok(Command::List(vec![])),
+			}
+		},
+		"MODE" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_modestring = this_message.params.get(1).cloned();
+				let this_params = this_message.params.iter().skip(2).cloned().collect();
+				Ok(Command::Mode(this_message.params[0].clone(), this_modestring, this_params))
+			}
+		},
+		"OPER" => {
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_name = this_message.params[0].clone();
+				let this_password = this_message.params[1].clone();
+				Ok(Command::Oper(this_name, this_password))
+			}
+		},
+		"KILL" => {
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_target = this_message.params[0].clone();
+				let this_comment = this_message.params[1].clone();
+				Ok(Command::Kill(this_target, this_comment))
+			}
+		},
+		"REHASH" => { Ok(Command::Rehash) },
+		"KICK" => {
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_comment = this_message.params.get(2).cloned();
+				Ok(Command::Kick(
+					split_list(&this_message.params[0]),
+					split_list(&this_message.params[1]),
+					this_comment))
+			}
+		},
+		"INVITE" => {
+			if num_param < 2 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				let this_nick = this_message.params[0].clone();
+				let this_channel = this_message.params[1].clone();
+				Ok(Command::Invite(this_nick, this_channel))
+			}
+		},
+		"AWAY" => {
+			match this_message.params.first() {
+				Some(text) if !text.is_empty() => Ok(Command::Away(Some(text.clone()))),
+				_ => Ok(Command::Away(None)),
+			}
+		},
+		"WHOWAS" => {
+			if num_param < 1 {
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else {
+				// a missing, zero or negative count means every entry
+				let this_count = this_message.params.get(1)
+					.and_then(|count| count.parse::<usize>().ok())
+					.unwrap_or(0);
+				Ok(Command::Whowas(split_list(&this_message.params[0]), this_count))
+			}
+		},
+		"WHO" => {
+			let this_mask = this_message.params.first().cloned();
+			let this_options = this_message.params.get(1).cloned();
+			Ok(Command::Who(this_mask, this_options))
+		},
+		"STATS" => {
+			let this_query = this_message.params.first().cloned();
+			Ok(Command::Stats(this_query))
+		},
+		"PING" => {
+			// a missing token is answered with ERR_NOORIGIN
+			let this_token = this_message.params.first().cloned().unwrap_or_default();
+			Ok(Command::Ping(this_token))
+		},
+		"PONG" => { Ok(Command::Pong) },
+		"MOTD" => { Ok(Command::Motd) },
+		"LUSERS" => { Ok(Command::Lusers) },
+		"ADMIN" => {
+			let this_server = this_message.params.first().cloned();
+			Ok(Command::Admin(this_server))
+		},
 		"WHOIS" => {
 			if num_param < 1 {
-				return Err("WHOIS needs a target");
+				Ok(Command::NeedMoreParams(this_message.command.clone()))
+			} else if num_param == 1 {
+				Ok(Command::Whois(None, split_list(&this_message.params[0])))
 			} else {
-				return Ok(Command::Whois(this_message.params[0].clone()));
+				let this_server = this_message.params[0].clone();
+				Ok(Command::Whois(Some(this_server), split_list(&this_message.params[1])))
 			}
 		}
-		_ => {return Ok(Command::Unknown(this_message.command));}
+		_ => { Ok(Command::Unknown(this_message.command)) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nicknames_follow_rfc2812() {
+		assert!(is_valid_nickname("bob", 9, CaseMapping::Rfc1459));
+		assert!(is_valid_nickname("[bob]-2", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("2bob", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("-bob", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("bob!", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("bobbobbobb", 9, CaseMapping::Rfc1459));
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn unicode_nicknames_need_rfc7613() {
+		assert!(is_valid_nickname("Élise", 9, CaseMapping::Rfc7613));
+		assert!(is_valid_nickname("ёжик2", 9, CaseMapping::Rfc7613));
+		assert!(!is_valid_nickname("2ёжик", 9, CaseMapping::Rfc7613));
+		assert!(!is_valid_nickname("Élise", 9, CaseMapping::Rfc1459));
+		assert!(!is_valid_nickname("Élise", 9, CaseMapping::Ascii));
+		// the limit counts characters, not bytes
+		assert!(is_valid_nickname("ééééééééé", 9, CaseMapping::Rfc7613));
+	}
+}
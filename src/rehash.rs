@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use config::{Config, ConfigLoader, SharedConfig};
+use connection::{format_host};
+use parser::{User};
+use sendq::{SendQueue};
+use tls::{TlsCertificates};
+use whowas::{WhowasHistory};
+
+/// Puts a freshly loaded configuration in place of the running one, on
+/// REHASH or SIGHUP. Connections look the configuration up each time they
+/// need it, so they stay up and carry on under the new settings, unless a
+/// new K-line matches them.
+#[derive(Clone)]
+pub struct Rehasher {
+	config: SharedConfig,
+	/// Without one, only the TLS certificates are reloaded.
+	loader: Option<ConfigLoader>,
+	tls: Option<Arc<TlsCertificates>>,
+	whowas: Arc<Mutex<WhowasHistory>>,
+	users: Arc<Mutex<HashMap<SocketAddr, User>>>,
+	phonebook: Arc<Mutex<HashMap<SocketAddr, SendQueue>>>,
+}
+
+impl Rehasher {
+	pub fn new(config: SharedConfig, tls: Option<Arc<TlsCertificates>>,
+			whowas: Arc<Mutex<WhowasHistory>>, users: Arc<Mutex<HashMap<SocketAddr, User>>>,
+			phonebook: Arc<Mutex<HashMap<SocketAddr, SendQueue>>>) -> Self {
+		Rehasher {config, loader: None, tls, whowas, users, phonebook}
+	}
+
+	pub fn set_loader(&mut self, loader: ConfigLoader) {
+		self.loader = Some(loader);
+	}
+
+	/// Load the configuration and TLS certificates again. If either is
+	/// unusable, the running ones are all kept and the reason is returned.
+	/// Otherwise the result names the settings that were changed but are
+	/// left alone until the server restarts.
+	pub fn rehash(&self) -> Result<Vec<&'static str>, String> {
+		let current = self.config.lock().unwrap().clone();
+		let mut config = match self.loader {
+			Some(ref loader) => loader()?,
+			None => (*current).clone(),
+		};
+
+		// the listeners are already bound, and the nickname and channel
+		// maps are keyed by the casemapping
+		let mut unchanged = vec![];
+		if config.server_name != current.server_name {
+			config.server_name = current.server_name.clone();
+			unchanged.push("server name");
+		}
+		if config.listeners != current.listeners {
+			config.listeners = current.listeners.clone();
+			unchanged.push("listeners");
+		}
+		if config.casemapping != current.casemapping {
+			config.casemapping = current.casemapping;
+			unchanged.push("casemapping");
+		}
+		for listener in &config.listeners {
+			if !config.classes.iter().any(|class| class.name == listener.class) {
+				return Err(format!("listener {} uses class \"{}\", which is no longer defined",
+					listener.address, listener.class));
+			}
+		}
+
+		if let Some(ref certificates) = self.tls {
+			certificates.reload(&config.tls_certificate, &config.tls_private_key)?;
+		}
+		{
+			let mut ww = self.whowas.lock().unwrap();
+			(*ww).set_capacity(config.whowas_length);
+		}
+		let config = Arc::new(config);
+		*self.config.lock().unwrap() = config.clone();
+		self.apply_klines(&config);
+		Ok(unchanged)
+	}
+
+	/// Disconnect everyone a K-line now matches, the way KILL does.
+	fn apply_klines(&self, config: &Config) {
+		let pb = self.phonebook.lock().unwrap();
+		let uu = self.users.lock().unwrap();
+		for (addr, user) in (*uu).iter() {
+			let host = format_host(addr);
+			let kline = config.klines.iter().find(|kline| kline.matches(&user.user, &host));
+			if let (Some(kline), Some(queue)) = (kline, (*pb).get(addr)) {
+				info!("{}@{} is K-lined: {}", user.user, host, kline.reason);
+				queue.kill(format!("K-Lined: {}", kline.reason));
+			}
+		}
+	}
+}
@@ -0,0 +1,260 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use mio::Token;
+
+use clock::unix_timestamp;
+
+/// Tokens of the connections with output waiting, for the reactor to flush.
+pub type PendingWrites = Arc<Mutex<Vec<Token>>>;
+
+/// Lines waiting to be written to one client's socket. Any connection may
+/// push to it through the phonebook; the reactor writes it out once the
+/// socket is ready for more. Holding more than `max_bytes` marks the
+/// queue as exceeded, and its client is then disconnected. A client can be
+/// KILLed the same way, by whoever holds its queue.
+#[derive(Clone)]
+pub struct SendQueue {
+	token: Token,
+	queue: Arc<Mutex<Queue>>,
+	pending: PendingWrites,
+}
+
+struct Queue {
+	buffer: Vec<u8>,
+	max_bytes: usize,
+	exceeded: bool,
+	/// Why the client was KILLed, until its connection acts on it.
+	killed: Option<String>,
+	stats: LinkStats,
+}
+
+/// Traffic counters for a client, as reported by STATS l.
+#[derive(Clone, Copy, Default)]
+pub struct LinkStats {
+	pub sent_messages: u64,
+	pub sent_bytes: u64,
+	pub received_messages: u64,
+	pub received_bytes: u64,
+	pub opened_at: u64,
+}
+
+impl SendQueue {
+	pub fn new(token: Token, pending: PendingWrites, max_bytes: usize) -> Self {
+		let queue = Queue {
+			buffer: vec![],
+			max_bytes,
+			exceeded: false,
+			killed: None,
+			stats: LinkStats {opened_at: unix_timestamp(), ..LinkStats::default()}};
+		SendQueue {token, queue: Arc::new(Mutex::new(queue)), pending}
+	}
+
+	pub fn token(&self) -> Token {
+		self.token
+	}
+
+	/// Queue a line, which must already end in CRLF. A line that would
+	/// take the queue past its limit is dropped and the queue marked
+	/// exceeded.
+	pub fn push(&self, line: &str) {
+		let needs_flush;
+		{
+			let mut queue = self.queue.lock().unwrap();
+			if queue.buffer.len() + line.len() > queue.max_bytes {
+				needs_flush = !queue.exceeded;
+				queue.exceeded = true;
+			} else {
+				needs_flush = queue.buffer.is_empty();
+				queue.buffer.extend_from_slice(line.as_bytes());
+				queue.stats.sent_messages += 1;
+			}
+		}
+		if needs_flush {
+			let mut pending = self.pending.lock().unwrap();
+			pending.push(self.token);
+		}
+	}
+
+	/// Hold up to `max_bytes` from now on. Whatever is queued already stays,
+	/// and counts towards the new limit.
+	pub fn set_max_bytes(&self, max_bytes: usize) {
+		self.queue.lock().unwrap().max_bytes = max_bytes;
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.queue.lock().unwrap().buffer.is_empty()
+	}
+
+	pub fn is_exceeded(&self) -> bool {
+		self.queue.lock().unwrap().exceeded
+	}
+
+	/// The number of bytes waiting to be written.
+	pub fn len(&self) -> usize {
+		self.queue.lock().unwrap().buffer.len()
+	}
+
+	/// Have the client disconnected for the given reason the next time its
+	/// queue is flushed.
+	pub fn kill(&self, reason: String) {
+		{
+			let mut queue = self.queue.lock().unwrap();
+			queue.killed = Some(reason);
+		}
+		let mut pending = self.pending.lock().unwrap();
+		pending.push(self.token);
+	}
+
+	pub fn take_kill(&self) -> Option<String> {
+		self.queue.lock().unwrap().killed.take()
+	}
+
+	/// Throw away everything queued, to make room for a parting message.
+	pub fn clear(&self) {
+		self.queue.lock().unwrap().buffer.clear();
+	}
+
+	pub fn record_received(&self, messages: u64, bytes: u64) {
+		let mut queue = self.queue.lock().unwrap();
+		queue.stats.received_messages += messages;
+		queue.stats.received_bytes += bytes;
+	}
+
+	pub fn stats(&self) -> LinkStats {
+		self.queue.lock().unwrap().stats
+	}
+
+	/// Write as much as the socket will take without blocking. Whatever is
+	/// left waits for the socket to become writable again.
+	pub fn flush_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+		let mut queue = self.queue.lock().unwrap();
+		let mut written = 0;
+		let result = loop {
+			if written == queue.buffer.len() {
+				break Ok(());
+			}
+			match writer.write(&queue.buffer[written..]) {
+				Ok(0) => { break Err(io::Error::from(io::ErrorKind::WriteZero)); },
+				Ok(n) => { written += n; },
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { break Ok(()); },
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+				Err(e) => { break Err(e); },
+			}
+		};
+		queue.buffer.drain(..written);
+		queue.stats.sent_bytes += written as u64;
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn queue(max_bytes: usize) -> (SendQueue, PendingWrites) {
+		let pending = Arc::new(Mutex::new(vec![]));
+		(SendQueue::new(Token(7), pending.clone(), max_bytes), pending)
+	}
+
+	#[test]
+	fn queues_lines_and_asks_for_a_flush_once() {
+		let (queue, pending) = queue(100);
+		queue.push("PING :a\r\n");
+		queue.push("PING :b\r\n");
+		assert_eq!(queue.len(), 18);
+		assert_eq!(*pending.lock().unwrap(), vec![Token(7)]);
+		assert_eq!(queue.stats().sent_messages, 2);
+
+		let mut written = vec![];
+		queue.flush_to(&mut written).unwrap();
+		assert_eq!(written, b"PING :a\r\nPING :b\r\n");
+		assert!(queue.is_empty());
+		assert_eq!(queue.stats().sent_bytes, 18);
+	}
+
+	#[test]
+	fn drops_the_line_that_would_exceed() {
+		let (queue, pending) = queue(20);
+		queue.push("PING :a\r\n");
+		queue.push("PING :b\r\n");
+		assert!(!queue.is_exceeded());
+		queue.push("PING :c\r\n");
+		assert!(queue.is_exceeded());
+		assert_eq!(queue.len(), 18);
+		assert_eq!(queue.stats().sent_messages, 2);
+		// once to flush the first line, and once more to act on the overflow
+		assert_eq!(*pending.lock().unwrap(), vec![Token(7), Token(7)]);
+		queue.push("PING :d\r\n");
+		assert_eq!(pending.lock().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn a_line_exactly_filling_the_queue_fits() {
+		let (queue, _) = queue(9);
+		queue.push("PING :a\r\n");
+		assert!(!queue.is_exceeded());
+		assert_eq!(queue.len(), 9);
+	}
+
+	#[test]
+	fn new_limit_counts_what_is_queued() {
+		let (queue, _) = queue(100);
+		queue.push("PING :a\r\n");
+		queue.set_max_bytes(15);
+		queue.push("PING :b\r\n");
+		assert!(queue.is_exceeded());
+		assert_eq!(queue.len(), 9);
+
+		let (queue, _) = self::queue(9);
+		queue.set_max_bytes(18);
+		queue.push("PING :a\r\n");
+		queue.push("PING :b\r\n");
+		assert!(!queue.is_exceeded());
+	}
+
+	#[test]
+	fn kill_is_taken_once() {
+		let (queue, pending) = queue(100);
+		queue.kill("Killed (boss (bye))".to_string());
+		assert_eq!(*pending.lock().unwrap(), vec![Token(7)]);
+		assert_eq!(queue.take_kill(), Some("Killed (boss (bye))".to_string()));
+		assert_eq!(queue.take_kill(), None);
+	}
+
+	/// Takes a few bytes at a time, then would block.
+	struct Trickle {
+		taken: Vec<u8>,
+		budget: usize,
+	}
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if self.budget == 0 {
+				return Err(io::Error::from(io::ErrorKind::WouldBlock));
+			}
+			let n = buf.len().min(4).min(self.budget);
+			self.budget -= n;
+			self.taken.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn keeps_what_the_socket_would_not_take() {
+		let (queue, _) = queue(100);
+		queue.push("PRIVMSG #x :hello\r\n");
+		let mut socket = Trickle {taken: vec![], budget: 10};
+		queue.flush_to(&mut socket).unwrap();
+		assert_eq!(socket.taken, b"PRIVMSG #x");
+		assert_eq!(queue.len(), 9);
+		socket.budget = 100;
+		queue.flush_to(&mut socket).unwrap();
+		assert_eq!(socket.taken, b"PRIVMSG #x :hello\r\n");
+		assert!(queue.is_empty());
+	}
+}
@@ -1,53 +1,389 @@
-use std::net::{TcpListener, SocketAddr};
-use std::collections::HashMap;
+use std::net::{SocketAddr};
+use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::sync::mpsc;
+use std::io;
+use std::time::Instant;
+
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use mio::net::TcpListener;
+use signal_hook::consts::SIGHUP;
+use signal_hook_mio::v1_0::Signals;
 
 use parser::{User};
 use connection::{Connection};
+use channel::{Channel};
+use config::{Config, Listener, SharedConfig};
+use whowas::{WhowasHistory};
+use casemap::{CaseKey};
+use sendq::{SendQueue, PendingWrites};
+use tls::{TlsCertificates, Transport};
+use rehash::{Rehasher};
+use oper::{PasswordChecker};
+use clock::{unix_timestamp};
+
+
+/// The times connections asked to be woken at, kept in order so that the
+/// next one is found without looking at them all.
+#[derive(Default)]
+struct Wakeups {
+	by_token: HashMap<Token, Instant>,
+	by_time: BTreeSet<(Instant, Token)>,
+}
+
+impl Wakeups {
+	fn set(&mut self, token: Token, at: Instant) {
+		if let Some(previous) = self.by_token.insert(token, at) {
+			self.by_time.remove(&(previous, token));
+		}
+		self.by_time.insert((at, token));
+	}
+
+	fn remove(&mut self, token: Token) {
+		if let Some(at) = self.by_token.remove(&token) {
+			self.by_time.remove(&(at, token));
+		}
+	}
+
+	fn next(&self) -> Option<Instant> {
+		self.by_time.iter().next().map(|&(at, _)| at)
+	}
+
+	/// Forget every wakeup due by `now`, returning their connections.
+	fn take_due(&mut self, now: Instant) -> Vec<Token> {
+		let later = self.by_time.split_off(&(now, Token(usize::MAX)));
+		let due = std::mem::replace(&mut self.by_time, later);
+		due.into_iter()
+			.map(|(_, token)| {
+				self.by_token.remove(&token);
+				token
+			})
+			.collect()
+	}
+}
+
+/// The token the password checking thread wakes the reactor with, after
+/// the listeners and SIGHUP.
+fn checked_token(config: &Config) -> Token {
+	Token(config.listeners.len() + 1)
+}
+
+/// Server-wide state shared between all connections.
+#[derive(Clone)]
+pub struct SharedState {
+	pub nicknames: Arc<Mutex<HashMap<CaseKey, SocketAddr>>>,
+	pub users: Arc<Mutex<HashMap<SocketAddr, User>>>,
+	pub channels: Arc<Mutex<HashMap<CaseKey, Channel>>>,
+	pub phonebook: Arc<Mutex<HashMap<SocketAddr, SendQueue>>>,
+	pub pending_writes: PendingWrites,
+	pub num_known_users: Arc<Mutex<usize>>,
+	pub whowas: Arc<Mutex<WhowasHistory>>,
+	pub config: SharedConfig,
+	pub password_checker: PasswordChecker,
+	/// When the server started, as a Unix timestamp.
+	pub started_at: u64,
+	/// Present if the server has a TLS listener.
+	pub tls: Option<Arc<TlsCertificates>>,
+	pub rehasher: Rehasher,
+}
 
 pub struct IrcServer {
-	nicknames: Arc<Mutex<HashMap<String, SocketAddr>>>, 
-	users: Arc<Mutex<HashMap<SocketAddr, User>>>,
-	phonebook: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<String>>>>,
-	num_known_users: Arc<Mutex<usize>>,
-	portnum: u16,
+	poll: Poll,
+	state: SharedState,
 }
 
 impl IrcServer {
-	pub fn new(portnum: u16) -> Self {
-		IrcServer { 
-			nicknames: Arc::new(Mutex::new(HashMap::new())),
-			users: Arc::new(Mutex::new(HashMap::new())),
-			phonebook: Arc::new(Mutex::new(HashMap::new())),
-			num_known_users: Arc::new(Mutex::new(0)),
-			portnum: portnum}
-	}
-
-	pub fn run(&mut self) {
-		let listener = TcpListener::bind(("127.0.0.1", self.portnum)).unwrap();
-	    for socket in listener.incoming() {
-	    	match socket {
-	    		Ok(stream) => {
-	    			stream.set_nonblocking(true).expect("set_nonblocking call failed");
-	    			let this_nicknames = self.nicknames.clone();
-	    			let this_users = self.users.clone();
-	    			let this_phonebook = self.phonebook.clone();
-	    			let this_num_known_users = self.num_known_users.clone();
-	    			let (tx, rx) = mpsc::channel();
-	    			{
-	    				let mut pb = self.phonebook.lock().unwrap();
-	    				(*pb).insert(stream.peer_addr().unwrap(), tx);
-	    			}
-
-	    			thread::spawn(|| {
-		    			let mut this_connection = Connection::new(stream, this_nicknames, this_users, rx, this_phonebook, this_num_known_users);
-		    			this_connection.handle_client();
-		    		});
-	    		},
-	    		Err(e) => error!("couldn't get client: {:?}", e),
-	    	}
-	    }
-	}
-}
\ No newline at end of file
+	/// Fails if the configuration has TLS listeners but its certificate or
+	/// key cannot be loaded.
+	pub fn new(config: Config) -> Result<Self, String> {
+		let poll = Poll::new().map_err(|e| format!("Couldn't create a poll instance: {}", e))?;
+		let waker = Waker::new(poll.registry(), checked_token(&config))
+			.map_err(|e| format!("Couldn't create a waker: {}", e))?;
+		let tls = if config.listeners.iter().any(|listener| listener.tls) {
+			let certificates = TlsCertificates::load(&config.tls_certificate, &config.tls_private_key)?;
+			Some(Arc::new(certificates))
+		} else {
+			None
+		};
+		let whowas = Arc::new(Mutex::new(WhowasHistory::new(config.whowas_length, config.casemapping)));
+		let config = Arc::new(Mutex::new(Arc::new(config)));
+		let users = Arc::new(Mutex::new(HashMap::new()));
+		let phonebook = Arc::new(Mutex::new(HashMap::new()));
+		let pending_writes = Arc::new(Mutex::new(vec![]));
+		Ok(IrcServer {
+			poll,
+			state: SharedState {
+				nicknames: Arc::new(Mutex::new(HashMap::new())),
+				channels: Arc::new(Mutex::new(HashMap::new())),
+				password_checker: PasswordChecker::spawn(pending_writes.clone(), waker),
+				pending_writes,
+				num_known_users: Arc::new(Mutex::new(0)),
+				rehasher: Rehasher::new(config.clone(), tls.clone(), whowas.clone(),
+					users.clone(), phonebook.clone()),
+				users,
+				phonebook,
+				whowas,
+				config,
+				started_at: unix_timestamp(),
+				tls}})
+	}
+
+	/// Have REHASH and SIGHUP build the configuration with `loader`, such
+	/// as by reading the configuration file again. Otherwise they only
+	/// reload the TLS certificates.
+	pub fn reload_config_with<F>(&mut self, loader: F)
+			where F: Fn() -> Result<Config, String> + Send + Sync + 'static {
+		self.state.rehasher.set_loader(Arc::new(loader));
+	}
+
+	/// Serve clients from a single thread, reacting to socket readiness
+	/// rather than polling each connection. Listener `i` is registered as
+	/// `Token(i)`, SIGHUP arrives on the token after them, finished
+	/// password checks on the one after that and connections take the
+	/// tokens after those. Only returns if a listener cannot be opened.
+	pub fn run(&mut self) -> Result<(), String> {
+		// the configuration connections were last brought up to date with.
+		// Listeners stay as they were at startup, whatever REHASH loads.
+		let mut config = self.state.config.lock().unwrap().clone();
+		let mut listeners = vec![];
+		for (i, listener_config) in config.listeners.iter().enumerate() {
+			let address = listener_config.address;
+			let mut listener = TcpListener::bind(address)
+				.map_err(|e| format!("Couldn't listen on {}: {}", address, e))?;
+			self.poll.registry().register(&mut listener, Token(i), Interest::READABLE).unwrap();
+			info!("listening on {}{}", address, if listener_config.tls { " (TLS)" } else { "" });
+			listeners.push(listener);
+		}
+
+		let hangup = Token(listeners.len());
+		let mut signals = Signals::new([SIGHUP]).expect("failed to watch for SIGHUP");
+		self.poll.registry().register(&mut signals, hangup, Interest::READABLE).unwrap();
+
+		let mut events = Events::with_capacity(1024);
+		let mut connections : HashMap<Token, Connection> = HashMap::new();
+		let checked = checked_token(&config);
+		let mut next_token = checked.0 + 1;
+		// connections to come back to at a given time, even if nothing happens
+		let mut wakeups = Wakeups::default();
+		loop {
+			let timeout = wakeups.next()
+				.map(|at| at.saturating_duration_since(Instant::now()));
+			if let Err(e) = self.poll.poll(&mut events, timeout) {
+				if e.kind() != io::ErrorKind::Interrupted {
+					error!("Poll Error: {}", e);
+				}
+				continue;
+			}
+
+			let mut touched = vec![];
+			for event in events.iter() {
+				match event.token() {
+					Token(i) if i < listeners.len() => {
+						// new connections need a wakeup to time out their registration
+						touched.extend(self.accept_clients(&listeners[i], &config.listeners[i],
+							self.poll.registry(), &mut connections, &mut next_token));
+					},
+					token if token == hangup => {
+						if signals.pending().count() > 0 {
+							self.rehash();
+						}
+					},
+					// the connections it was for are in the pending writes
+					token if token == checked => {},
+					token => {
+						if let Some(connection) = connections.get_mut(&token) {
+							if event.is_readable() || event.is_read_closed() || event.is_error() {
+								connection.handle_readable();
+							}
+							// reading may have produced output of its own, such as a TLS handshake
+							connection.flush(self.poll.registry());
+							touched.push(token);
+						}
+					},
+				}
+			}
+
+			let now = Instant::now();
+			for token in wakeups.take_due(now) {
+				if let Some(connection) = connections.get_mut(&token) {
+					connection.handle_timeout();
+					touched.push(token);
+				}
+			}
+
+			// anything a connection said to another is written out now, and
+			// finished password checks acted on. A flush can drop its client
+			// for a KILL or an exceeded SendQ, whose QUIT then has to go out to
+			// its peers too.
+			loop {
+				let pending : Vec<Token>;
+				{
+					let mut pw = self.state.pending_writes.lock().unwrap();
+					pending = (*pw).drain(..).collect();
+				}
+				if pending.is_empty() {
+					break;
+				}
+				for token in pending {
+					if let Some(connection) = connections.get_mut(&token) {
+						connection.finish_password_check();
+						connection.flush(self.poll.registry());
+						touched.push(token);
+					}
+				}
+			}
+
+			// after a REHASH or SIGHUP, connections take up their class's new
+			// limits, and may need waking at a different time
+			let current = self.state.config.lock().unwrap().clone();
+			if !Arc::ptr_eq(&current, &config) {
+				config = current;
+				for (&token, connection) in connections.iter_mut() {
+					connection.reload_class();
+					touched.push(token);
+				}
+			}
+
+			for token in touched {
+				let (closing, wakeup_at) = match connections.get(&token) {
+					Some(connection) => (connection.is_closing(), connection.wakeup_at()),
+					None => { continue; },
+				};
+				if closing {
+					wakeups.remove(token);
+					if let Some(mut connection) = connections.remove(&token) {
+						// a last chance for its parting words to go out
+						connection.flush(self.poll.registry());
+						if let Err(e) = self.poll.registry().deregister(&mut connection) {
+							error!("Deregister Error: {}", e);
+						}
+					}
+				} else if let Some(at) = wakeup_at {
+					wakeups.set(token, at);
+				} else {
+					wakeups.remove(token);
+				}
+			}
+		}
+	}
+
+	fn rehash(&self) {
+		info!("got SIGHUP, reloading the configuration");
+		match self.state.rehasher.rehash() {
+			Ok(unchanged) => {
+				for setting in unchanged {
+					warn!("the {} cannot change until the server restarts", setting);
+				}
+			},
+			Err(e) => { error!("keeping the current configuration: {}", e); },
+		}
+	}
+
+	/// Take every waiting client off the listener. It is edge-triggered, so
+	/// this must continue until it would block. Returns the new connections.
+	fn accept_clients(&self, listener: &TcpListener, listener_config: &Listener, registry: &Registry,
+			connections: &mut HashMap<Token, Connection>, next_token: &mut usize) -> Vec<Token> {
+		let mut accepted = vec![];
+		loop {
+			let (mut stream, peer_addr) = match listener.accept() {
+				Ok(client) => client,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { return accepted; },
+				Err(e) => {
+					error!("couldn't get client: {:?}", e);
+					return accepted;
+				},
+			};
+			let token = Token(*next_token);
+			*next_token += 1;
+			if let Err(e) = registry.register(&mut stream, token, Interest::READABLE) {
+				error!("Register Error: {}", e);
+				continue;
+			}
+			let stream = match self.state.tls {
+				Some(ref certificates) if listener_config.tls => match certificates.accept() {
+					Ok(session) => Transport::Tls(stream, Box::new(session)),
+					Err(e) => {
+						error!("TLS Error: {}", e);
+						continue;
+					},
+				},
+				_ => Transport::Plain(stream),
+			};
+			let class = self.state.config.lock().unwrap().class(&listener_config.class);
+			let sendq = SendQueue::new(token, self.state.pending_writes.clone(), class.max_sendq);
+			{
+				let mut pb = self.state.phonebook.lock().unwrap();
+				(*pb).insert(peer_addr, sendq.clone());
+			}
+			connections.insert(token, Connection::new(stream, peer_addr, class, sendq, self.state.clone()));
+			accepted.push(token);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpStream;
+	use std::thread;
+	use std::time::Duration;
+
+	use bcrypt;
+	use oper::{Operator};
+
+	/// Start a server on a free port and connect a client to it.
+	fn start(config: Config) -> TcpStream {
+		let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let config = Config {listeners: vec![Listener::new(address, false)], ..config};
+		let mut server = IrcServer::new(config).unwrap();
+		thread::spawn(move || server.run());
+		for _ in 0..50 {
+			if let Ok(stream) = TcpStream::connect(address) {
+				stream.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+				return stream;
+			}
+			thread::sleep(Duration::from_millis(20));
+		}
+		panic!("the server did not start");
+	}
+
+	/// Read replies up to and including the first with one of `numerics`.
+	fn read_until(reader: &mut BufReader<TcpStream>, numerics: &[&str]) -> Vec<String> {
+		let mut lines = vec![];
+		loop {
+			let mut line = String::new();
+			assert!(reader.read_line(&mut line).unwrap() > 0, "disconnected after {:?}", lines);
+			let done = numerics.iter().any(|numeric| line.split(' ').nth(1) == Some(*numeric));
+			lines.push(line);
+			if done {
+				return lines;
+			}
+		}
+	}
+
+	#[test]
+	fn pass_then_oper_with_hashed_passwords() {
+		let hash = bcrypt::hash("secret", 4).unwrap();
+		let mut stream = start(Config {
+			password: Some(hash.clone()),
+			operators: vec![Operator::new("boss".to_string(), hash)],
+			..Config::default()});
+		// all at once, so that both checks come from the same address together
+		stream.write_all(b"PASS secret\r\nNICK alice\r\nUSER a 0 * :Alice\r\nOPER boss secret\r\n").unwrap();
+		let mut reader = BufReader::new(stream);
+		let registered = read_until(&mut reader, &["001", "464"]);
+		assert!(registered.last().unwrap().contains(" 001 "), "{:?}", registered);
+		let opered = read_until(&mut reader, &["381", "464"]);
+		assert!(opered.last().unwrap().contains(" 381 "), "{:?}", opered);
+	}
+
+	#[test]
+	fn wrong_pass_is_refused() {
+		let mut stream = start(Config {
+			password: Some(bcrypt::hash("secret", 4).unwrap()),
+			..Config::default()});
+		stream.write_all(b"PASS guess\r\nNICK alice\r\nUSER a 0 * :Alice\r\n").unwrap();
+		let lines = read_until(&mut BufReader::new(stream), &["001", "464"]);
+		assert!(lines.last().unwrap().contains(" 464 "), "{:?}", lines);
+	}
+}
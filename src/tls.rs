@@ -0,0 +1,266 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use mio::{Interest, Registry, Token};
+use mio::event::Source;
+use mio::net::TcpStream;
+use rustls::{ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+/// The certificate chain and private key served on TLS listeners.
+/// Reloading them only affects connections accepted afterwards; those
+/// already open carry on with the configuration they started with.
+pub struct TlsCertificates {
+	current: Mutex<Arc<ServerConfig>>,
+}
+
+impl TlsCertificates {
+	pub fn load(certificate_path: &str, private_key_path: &str) -> Result<Self, String> {
+		let current = server_config(certificate_path, private_key_path)?;
+		Ok(TlsCertificates {current: Mutex::new(current)})
+	}
+
+	/// Read the files, which may have moved, again. If they are no longer
+	/// usable, the ones already loaded are kept.
+	pub fn reload(&self, certificate_path: &str, private_key_path: &str) -> Result<(), String> {
+		let reloaded = server_config(certificate_path, private_key_path)?;
+		*self.current.lock().unwrap() = reloaded;
+		Ok(())
+	}
+
+	/// Begin the server side of a handshake with a newly accepted client.
+	pub fn accept(&self) -> Result<ServerConnection, rustls::Error> {
+		let config = self.current.lock().unwrap().clone();
+		ServerConnection::new(config)
+	}
+}
+
+fn server_config(certificate_path: &str, private_key_path: &str) -> Result<Arc<ServerConfig>, String> {
+	let chain = CertificateDer::pem_file_iter(certificate_path)
+		.and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+		.map_err(|e| format!("{}: {}", certificate_path, e))?;
+	if chain.is_empty() {
+		return Err(format!("{}: no certificates found", certificate_path));
+	}
+	let key = PrivateKeyDer::from_pem_file(private_key_path)
+		.map_err(|e| format!("{}: {}", private_key_path, e))?;
+	let config = ServerConfig::builder()
+		.with_no_client_auth()
+		.with_single_cert(chain, key)
+		.map_err(|e| format!("{}: {}", certificate_path, e))?;
+	Ok(Arc::new(config))
+}
+
+/// A client's socket, with TLS layered over it if the client came in on
+/// the TLS listener. Reads and writes carry plaintext either way.
+pub enum Transport {
+	Plain(TcpStream),
+	Tls(TcpStream, Box<ServerConnection>),
+}
+
+impl Transport {
+	pub fn is_secure(&self) -> bool {
+		match *self {
+			Transport::Plain(_) => false,
+			Transport::Tls(..) => true,
+		}
+	}
+
+	/// Whether encrypted records are waiting for the socket to take them.
+	pub fn wants_write(&self) -> bool {
+		match *self {
+			Transport::Plain(_) => false,
+			Transport::Tls(_, ref session) => session.wants_write(),
+		}
+	}
+
+	/// Send as many waiting encrypted records as the socket will take.
+	/// Whatever is left waits for the socket to become writable again.
+	pub fn write_pending(&mut self) -> io::Result<()> {
+		match *self {
+			Transport::Plain(_) => Ok(()),
+			Transport::Tls(ref mut stream, ref mut session) => {
+				match write_records(stream, session) {
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+					result => result,
+				}
+			},
+		}
+	}
+
+	fn stream(&mut self) -> &mut TcpStream {
+		match *self {
+			Transport::Plain(ref mut stream) => stream,
+			Transport::Tls(ref mut stream, _) => stream,
+		}
+	}
+}
+
+fn write_records(stream: &mut TcpStream, session: &mut ServerConnection) -> io::Result<()> {
+	while session.wants_write() {
+		session.write_tls(stream)?;
+	}
+	Ok(())
+}
+
+impl Read for Transport {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let (stream, session) = match *self {
+			Transport::Plain(ref mut stream) => { return stream.read(buf); },
+			Transport::Tls(ref mut stream, ref mut session) => (stream, session),
+		};
+		loop {
+			match session.reader().read(buf) {
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+				result => { return result; },
+			}
+			if session.read_tls(stream)? == 0 {
+				return Ok(0);
+			}
+			let processed = session.process_new_packets();
+			// handshake messages and alerts go out as soon as they are made
+			if let Err(e) = write_records(stream, session) {
+				if e.kind() != io::ErrorKind::WouldBlock {
+					return Err(e);
+				}
+			}
+			if let Err(e) = processed {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+			}
+		}
+	}
+}
+
+impl Write for Transport {
+	/// Earlier records must reach the socket before more plaintext is
+	/// taken, so TLS buffers no more than one write's worth.
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let (stream, session) = match *self {
+			Transport::Plain(ref mut stream) => { return stream.write(buf); },
+			Transport::Tls(ref mut stream, ref mut session) => (stream, session),
+		};
+		write_records(stream, session)?;
+		let written = session.writer().write(buf)?;
+		if written == 0 && !buf.is_empty() {
+			// still handshaking, with as much plaintext held back as allowed
+			return Err(io::Error::from(io::ErrorKind::WouldBlock));
+		}
+		if let Err(e) = write_records(stream, session) {
+			if e.kind() != io::ErrorKind::WouldBlock {
+				return Err(e);
+			}
+		}
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			Transport::Plain(ref mut stream) => stream.flush(),
+			Transport::Tls(ref mut stream, ref mut session) => write_records(stream, session),
+		}
+	}
+}
+
+/// Lets the reactor register a transport by its socket.
+impl Source for Transport {
+	fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+		self.stream().register(registry, token, interests)
+	}
+
+	fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+		self.stream().reregister(registry, token, interests)
+	}
+
+	fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+		self.stream().deregister(registry)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::convert::TryFrom;
+	use std::env;
+	use std::fs;
+	use std::io::{BufRead, BufReader};
+	use std::net;
+	use std::process;
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use mio::net::TcpListener;
+	use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+	use rustls::pki_types::ServerName;
+
+	/// Retry a nonblocking operation until it stops blocking.
+	fn retry<T, F>(mut operation: F) -> io::Result<T> where F: FnMut() -> io::Result<T> {
+		let deadline = Instant::now() + Duration::from_secs(10);
+		loop {
+			match operation() {
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+					thread::sleep(Duration::from_millis(5));
+				},
+				result => { return result; },
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+		let directory = env::temp_dir();
+		let certificate_path = directory.join(format!("rustirc-test-{}-cert.pem", process::id()));
+		let private_key_path = directory.join(format!("rustirc-test-{}-key.pem", process::id()));
+		fs::write(&certificate_path, generated.cert.pem()).unwrap();
+		fs::write(&private_key_path, generated.key_pair.serialize_pem()).unwrap();
+		let certificates = TlsCertificates::load(certificate_path.to_str().unwrap(),
+			private_key_path.to_str().unwrap());
+		fs::remove_file(&certificate_path).unwrap();
+		fs::remove_file(&private_key_path).unwrap();
+		let certificates = certificates.unwrap();
+
+		let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+		let address = listener.local_addr().unwrap();
+		let mut roots = RootCertStore::empty();
+		roots.add(generated.cert.der().clone()).unwrap();
+		let client = thread::spawn(move || {
+			let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+			let session = ClientConnection::new(Arc::new(config),
+				ServerName::try_from("localhost").unwrap()).unwrap();
+			let mut stream = StreamOwned::new(session, net::TcpStream::connect(address).unwrap());
+			stream.write_all(b"PING :hello\r\n").unwrap();
+			let mut reply = String::new();
+			BufReader::new(stream).read_line(&mut reply).unwrap();
+			reply
+		});
+
+		let (stream, _) = retry(|| listener.accept()).unwrap();
+		let mut transport = Transport::Tls(stream, Box::new(certificates.accept().unwrap()));
+		assert!(transport.is_secure());
+		let mut received = vec![];
+		while !received.ends_with(b"\r\n") {
+			let mut buf = [0; 512];
+			let read = retry(|| transport.read(&mut buf)).unwrap();
+			assert!(read > 0, "client hung up");
+			received.extend_from_slice(&buf[..read]);
+		}
+		assert_eq!(received, b"PING :hello\r\n");
+
+		let reply = b"PONG :hello\r\n";
+		let written = retry(|| transport.write(reply)).unwrap();
+		assert_eq!(written, reply.len());
+		retry(|| transport.flush()).unwrap();
+		assert_eq!(client.join().unwrap(), "PONG :hello\r\n");
+	}
+
+	#[test]
+	fn load_reports_unusable_files() {
+		let missing = env::temp_dir().join(format!("rustirc-test-{}-missing.pem", process::id()));
+		let missing = missing.to_str().unwrap();
+		match TlsCertificates::load(missing, missing) {
+			Ok(_) => panic!("loaded a missing certificate"),
+			Err(e) => assert!(e.starts_with(missing), "{}", e),
+		}
+	}
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+use casemap::CaseMapping;
+use clock::unix_timestamp;
+
+/// What we remember about a nickname after its owner quits or changes nick.
+#[derive(Clone)]
+pub struct WhowasEntry {
+	pub nick: String,
+	pub user: String,
+	pub host: String,
+	pub realname: String,
+	pub server: String,
+	pub departed_at: u64,
+}
+
+impl WhowasEntry {
+	pub fn new(nick: String, user: String, host: String, realname: String, server: String) -> Self {
+		WhowasEntry {nick, user, host, realname, server, departed_at: unix_timestamp()}
+	}
+}
+
+/// A ring buffer of departed nicknames, oldest first.
+pub struct WhowasHistory {
+	entries: VecDeque<WhowasEntry>,
+	capacity: usize,
+	casemapping: CaseMapping,
+}
+
+impl WhowasHistory {
+	pub fn new(capacity: usize, casemapping: CaseMapping) -> Self {
+		WhowasHistory {entries: VecDeque::with_capacity(capacity), capacity, casemapping}
+	}
+
+	pub fn record(&mut self, entry: WhowasEntry) {
+		if self.capacity == 0 {
+			return;
+		}
+		if self.entries.len() == self.capacity {
+			self.entries.pop_front();
+		}
+		self.entries.push_back(entry);
+	}
+
+	/// Remember a different number of nicknames from now on, forgetting the
+	/// oldest if there are too many.
+	pub fn set_capacity(&mut self, capacity: usize) {
+		while self.entries.len() > capacity {
+			self.entries.pop_front();
+		}
+		self.capacity = capacity;
+	}
+
+	/// The most recent entries for a nickname, newest first. A count of
+	/// zero returns them all.
+	pub fn lookup(&self, nick: &str, count: usize) -> Vec<WhowasEntry> {
+		let nick = self.casemapping.fold(nick);
+		let matching = self.entries.iter().rev()
+			.filter(|entry| self.casemapping.fold(&entry.nick) == nick)
+			.cloned();
+		if count == 0 {
+			matching.collect()
+		} else {
+			matching.take(count).collect()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// An entry told apart from others with the same nickname by its user.
+	fn entry(nick: &str, user: &str) -> WhowasEntry {
+		WhowasEntry::new(nick.to_string(), user.to_string(), "host".to_string(),
+			"Real Name".to_string(), "irc.example.net".to_string())
+	}
+
+	fn users(entries: Vec<WhowasEntry>) -> Vec<String> {
+		entries.into_iter().map(|entry| entry.user).collect()
+	}
+
+	#[test]
+	fn looks_up_newest_first() {
+		let mut history = WhowasHistory::new(10, CaseMapping::Rfc1459);
+		history.record(entry("bob", "first"));
+		history.record(entry("alice", "other"));
+		history.record(entry("bob", "second"));
+		history.record(entry("bob", "third"));
+		assert_eq!(users(history.lookup("bob", 0)), vec!["third", "second", "first"]);
+		assert_eq!(users(history.lookup("alice", 0)), vec!["other"]);
+		assert!(history.lookup("carol", 0).is_empty());
+	}
+
+	#[test]
+	fn count_limits_the_entries() {
+		let mut history = WhowasHistory::new(10, CaseMapping::Rfc1459);
+		for user in &["first", "second", "third"] {
+			history.record(entry("bob", user));
+		}
+		assert_eq!(users(history.lookup("bob", 2)), vec!["third", "second"]);
+		assert_eq!(users(history.lookup("bob", 5)).len(), 3);
+	}
+
+	#[test]
+	fn looks_up_by_casemapping() {
+		let mut history = WhowasHistory::new(10, CaseMapping::Rfc1459);
+		history.record(entry("[Bob]", "bob"));
+		assert_eq!(users(history.lookup("{bob}", 0)), vec!["bob"]);
+		assert_eq!(history.lookup("{bob}", 0)[0].nick, "[Bob]");
+
+		let mut history = WhowasHistory::new(10, CaseMapping::Ascii);
+		history.record(entry("[Bob]", "bob"));
+		assert_eq!(users(history.lookup("[BOB]", 0)), vec!["bob"]);
+		assert!(history.lookup("{bob}", 0).is_empty());
+	}
+
+	#[test]
+	fn forgets_the_oldest_at_capacity() {
+		let mut history = WhowasHistory::new(2, CaseMapping::Rfc1459);
+		history.record(entry("alice", "alice"));
+		history.record(entry("bob", "bob"));
+		history.record(entry("carol", "carol"));
+		assert!(history.lookup("alice", 0).is_empty());
+		assert_eq!(users(history.lookup("bob", 0)), vec!["bob"]);
+		assert_eq!(users(history.lookup("carol", 0)), vec!["carol"]);
+	}
+
+	#[test]
+	fn zero_capacity_remembers_nothing() {
+		let mut history = WhowasHistory::new(0, CaseMapping::Rfc1459);
+		history.record(entry("bob", "bob"));
+		assert!(history.lookup("bob", 0).is_empty());
+	}
+
+	#[test]
+	fn shrinking_forgets_the_oldest() {
+		let mut history = WhowasHistory::new(4, CaseMapping::Rfc1459);
+		for user in &["first", "second", "third", "fourth"] {
+			history.record(entry("bob", user));
+		}
+		history.set_capacity(2);
+		assert_eq!(users(history.lookup("bob", 0)), vec!["fourth", "third"]);
+		history.record(entry("bob", "fifth"));
+		assert_eq!(users(history.lookup("bob", 0)), vec!["fifth", "fourth"]);
+
+		history.set_capacity(3);
+		history.record(entry("bob", "sixth"));
+		assert_eq!(users(history.lookup("bob", 0)), vec!["sixth", "fifth", "fourth"]);
+	}
+}